@@ -1,17 +1,131 @@
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::num::ParseIntError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, OnceLock};
 use std::thread;
+use std::time::{Duration, Instant};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use tungstenite::Message;
 
 
 
-const BOARD_SIZE: usize = 31;
-const CENTER: usize = 15;
+const DEFAULT_BOARD_SIZE: usize = 31;
+const MIN_BOARD_SIZE: usize = 5;
+const MAX_BOARD_SIZE: usize = 64;
+const DIRECTIONS: [(isize, isize); 4] = [(1, 0), (0, 1), (1, 1), (1, -1)];
+const DEFAULT_DEPTH: u32 = 3;
+const DEFAULT_TIME_BUDGET_MS: u64 = 1000;
+const MAX_CANDIDATES: usize = 15;
+const DEFAULT_RADIUS: usize = 2;
+const WIN_SCORE: i32 = 1_000_000;
+const INF: i32 = i32::MAX / 2;
+const VCF_DEPTH: u32 = 12;
+const VCT_DEPTH: u32 = 3;
+const MAX_DEPTH: usize = 16;
+const DEFAULT_CENTER_BIAS: i32 = 1;
+const MAX_EXTENSIONS: u32 = 4;
+const QUIESCENCE_DEPTH: u32 = 6;
+const OPENING_PLIES: usize = 8;
+const OPENING_CONTACT_BONUS: i32 = 200;
+const ASPIRATION_WINDOW: i32 = 5_000;
+const MAX_THREE_BIAS: i64 = 20;
+const MIN_THREES_SEEN: u32 = 3;
+const PROTOCOL_VERSION: u32 = 1;
+const MAX_RENDER: usize = 20;
+const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+const MAX_SELF_PLAY_PLIES: usize = 400;
+const QUEUE_PER_WORKER: usize = 4;
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+// `--flat-errors`: errors as a bare message string, as before they carried a code.
+static FLAT_ERRORS: AtomicBool = AtomicBool::new(false);
+
+// `--log <error|info|debug>`: failures only, plus connection lifecycle, plus each decision.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum LogLevel {
+    Error,
+    Info,
+    Debug,
+}
+
+static LOG_LEVEL: OnceLock<LogLevel> = OnceLock::new();
+
+// Tests log into their own thread, at a level of their own, and leave the process-wide one alone.
+#[cfg(test)]
+thread_local! {
+    static TEST_LOG_LEVEL: std::cell::Cell<Option<LogLevel>> = const { std::cell::Cell::new(None) };
+    static TEST_LOG: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+fn log_enabled(level: LogLevel) -> bool {
+    #[cfg(test)]
+    if let Some(test_level) = TEST_LOG_LEVEL.get() {
+        return level <= test_level;
+    }
+    level <= *LOG_LEVEL.get().unwrap_or(&LogLevel::Info)
+}
+
+fn write_log(line: std::fmt::Arguments) {
+    #[cfg(test)]
+    TEST_LOG.with_borrow_mut(|lines| lines.push(line.to_string()));
+    #[cfg(not(test))]
+    eprintln!("{}", line);
+}
+
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        if log_enabled(LogLevel::$level) {
+            write_log(format_args!($($arg)*));
+        }
+    };
+}
+
+// A thread that panics while holding a lock poisons it; carry on with the data rather than let
+// every later lock panic too and take the other games down with it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        log!(Error, "Recovering a lock poisoned by a panicked thread");
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+static ZOBRIST: OnceLock<Vec<u64>> = OnceLock::new();
+
+// How classify_line reads every possible 9-cell window, indexed by the window's code: in base 3,
+// first cell lowest, a digit is 0 for an empty cell, 1 for the side's own stone and 2 for the other
+// side's stone or the edge.
+static LINE_THREATS: OnceLock<Vec<LineThreat>> = OnceLock::new();
+
+fn line_threat(code: usize) -> LineThreat {
+    let table = LINE_THREATS.get_or_init(|| {
+        (0..3usize.pow(9))
+            .map(|code| {
+                let line: Vec<char> = (0..9).map(|i| ['.', 'X', 'B'][code / 3usize.pow(i) % 3]).collect();
+                GameState::classify_line(&line, Player::Me)
+            })
+            .collect()
+    });
+    table[code]
+}
+
+fn zobrist_key(x: usize, y: usize, player: Player) -> u64 {
+    let table = ZOBRIST.get_or_init(|| {
+        let mut rng = StdRng::seed_from_u64(0x5eed_c4ab);
+        (0..MAX_BOARD_SIZE * MAX_BOARD_SIZE * 2).map(|_| rng.r#gen()).collect()
+    });
+    table[(y * MAX_BOARD_SIZE + x) * 2 + usize::from(player.is_my())]
+}
 
 #[derive(Deserialize, Clone, Debug)]
 #[serde(untagged)]
@@ -20,10 +134,10 @@ enum StrOrUsize {
     Num(usize),
 }
 impl StrOrUsize {
-    fn as_usize(&self) -> usize {
+    fn as_usize(&self) -> Result<usize, ParseIntError> {
         match self {
-            StrOrUsize::Str(s) => s.parse::<usize>().unwrap(),
-            StrOrUsize::Num(n) => *n,
+            StrOrUsize::Str(s) => s.trim().parse::<usize>(),
+            StrOrUsize::Num(n) => Ok(*n),
         }
     }
 }
@@ -32,8 +146,21 @@ struct CoordIn {
     x: StrOrUsize,
     y: StrOrUsize,
 }
+impl CoordIn {
+    fn to_xy(&self) -> Result<(usize, usize), ParseIntError> {
+        Ok((self.x.as_usize()?, self.y.as_usize()?))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct GameMoveIn {
+    x: StrOrUsize,
+    y: StrOrUsize,
+    #[serde(default)]
+    side: Option<Player>,
+}
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Clone, Debug, PartialEq)]
 struct CoordOut {
     x: usize,
     y: usize,
@@ -47,311 +174,5247 @@ impl CoordOut {
 #[derive(Deserialize)]
 struct Command {
     command: String,
+    // `move` is left to the cell that is_legal and threats ask about.
+    #[serde(default, rename = "opponentMove", alias = "opponent_move", alias = "lastMove", alias = "last_move")]
+    opponent_move: Option<CoordIn>,
+    #[serde(default)]
+    session: Option<SessionId>,
+    #[serde(default)]
+    choice: Option<String>,
     #[serde(default)]
-    opponentMove: Option<CoordIn>,
+    stones: Option<Vec<CoordIn>>,
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    n: Option<usize>,
+    #[serde(default)]
+    first: Option<bool>,
+    #[serde(default)]
+    moves: Option<Vec<GameMoveIn>>,
+    #[serde(default)]
+    color: Option<Color>,
+    #[serde(default)]
+    r#move: Option<CoordIn>,
+    #[serde(default)]
+    force: bool,
+    #[serde(default = "protocol_version")]
+    version: u32,
+}
+
+fn protocol_version() -> u32 {
+    PROTOCOL_VERSION
+}
+
+fn default_use_killers() -> bool {
+    true
+}
+
+fn default_center_bias() -> i32 {
+    DEFAULT_CENTER_BIAS
+}
+
+// Every JSON reply carries the protocol version alongside its own fields.
+#[derive(Serialize)]
+struct Versioned<'a, T> {
+    version: u32,
+    #[serde(flatten)]
+    body: &'a T,
 }
 
 #[derive(Serialize)]
 struct MoveResponse {
     r#move: CoordOut,
-    team: &'static str,
+    team: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    winner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<&'static str>,
+    // Deepest completed search iteration, with --report-depth; 0 when no search was needed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    depth: Option<u32>,
 }
 
 #[derive(Serialize)]
-struct Reply {
-    reply: String,
+struct GameOver {
+    winner: &'static str,
 }
 
+#[derive(Serialize)]
+struct DrawResponse {
+    result: &'static str,
+}
 
-#[derive(Clone)]
-struct GameState {
-    my_board: Vec<Vec<bool>>, 
-    opponent_moves: Vec<(usize, usize)>, 
-    first_move: bool, 
-    total_moves: Vec<(usize, usize)>, 
+#[derive(Serialize)]
+struct BoardResponse {
+    width: usize,
+    height: usize,
+    moves: usize,
+    my: Vec<CoordOut>,
+    opponent: Vec<CoordOut>,
 }
-enum LineThreat {
-    Five,
-    OpenFour,
-    BlockedFour,
-    OpenThree,
-    BlockedThree,
-    Two,
-    Other,
+
+struct RootSearch {
+    best_score: i32,
+    best_moves: Vec<(usize, usize)>,
+    scored: Vec<(i32, (usize, usize))>,
 }
 
+#[derive(Serialize)]
+struct ScoredMove {
+    x: usize,
+    y: usize,
+    score: i32,
+}
 
+#[derive(Serialize)]
+struct HeatmapResponse {
+    cells: Vec<ScoredMove>,
+}
 
-impl GameState {
-    fn new() -> Self {
-        Self {
-            my_board: vec![vec![false; BOARD_SIZE]; BOARD_SIZE],
-            opponent_moves: Vec::new(),
-            first_move: true,
-            total_moves: Vec::new(),
-        }
-    }
+#[derive(Serialize)]
+struct LegalityResponse {
+    legal: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+}
 
-    fn reset(&mut self) {
-        for row in &mut self.my_board {
-            row.fill(false);
-        }
-        self.opponent_moves.clear();
-        self.first_move = true;
-        self.total_moves.clear();
+#[derive(Serialize)]
+struct DirectionThreats {
+    dx: isize,
+    dy: isize,
+    mine: LineThreat,
+    opponent: LineThreat,
+}
+
+#[derive(Serialize)]
+struct ThreatsResponse {
+    directions: Vec<DirectionThreats>,
+}
+
+#[derive(Serialize)]
+struct ThreatInfo {
+    player: Player,
+    threat: LineThreat,
+    cells: Vec<CoordOut>,
+}
+
+#[derive(Serialize)]
+struct ThreatListResponse {
+    threats: Vec<ThreatInfo>,
+}
+
+// The board's threats summed run by run from our side: ours count for us, theirs against.
+#[derive(Serialize)]
+struct EvalResponse {
+    score: i32,
+}
+
+#[derive(Serialize)]
+struct ExportResponse {
+    sgf: String,
+}
+
+#[derive(Serialize)]
+struct SelfPlayResponse {
+    moves: Vec<CoordOut>,
+    result: &'static str,
+}
+
+#[derive(Serialize)]
+struct HintResponse {
+    hint: CoordOut,
+}
+
+#[derive(Serialize)]
+struct RefuteResponse {
+    refutation: CoordOut,
+}
+
+#[derive(Serialize)]
+struct AnalyzeResponse {
+    best_move: Option<CoordOut>,
+    score: i32,
+    pv: Vec<CoordOut>,
+}
+
+#[derive(Serialize, Default)]
+struct DecisionInfo {
+    r#move: Option<CoordOut>,
+    score: i32,
+    reason: &'static str,
+    depth: u32,
+    nodes: u64,
+    runners_up: Vec<ScoredMove>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    vcf: Vec<CoordOut>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pv: Vec<CoordOut>,
+}
+
+// `--strategy <heuristic|mirror>`: mirror answers each opponent move with its reflection through
+// the center when that cell is free, as a weak sparring partner, and searches otherwise.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum Strategy {
+    #[default]
+    Heuristic,
+    Mirror,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Color {
+    Black,
+    White,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Player {
+    Me,
+    Opponent,
+}
+
+impl Player {
+    fn from_is_my(is_my: bool) -> Self {
+        if is_my { Player::Me } else { Player::Opponent }
     }
-    fn is_my_move(&self, x: usize, y: usize) -> bool {
-        self.my_board[y][x]
+
+    fn is_my(self) -> bool {
+        self == Player::Me
     }
 
-    fn is_opponent_move(&self, x: usize, y: usize) -> bool {
-        self.opponent_moves.contains(&(x, y))
+    fn other(self) -> Self {
+        match self {
+            Player::Me => Player::Opponent,
+            Player::Opponent => Player::Me,
+        }
     }
 
-    fn is_empty(&self, x: usize, y: usize) -> bool {
-        !self.is_my_move(x, y) && !self.is_opponent_move(x, y)
+    // How the side's stones are written in a line window and on the rendered board.
+    fn stone(self) -> char {
+        match self {
+            Player::Me => 'X',
+            Player::Opponent => 'O',
+        }
     }
+}
 
-    
-fn evaluate_line_type(&self, x: usize, y: usize, dx: isize, dy: isize, is_my: bool) -> LineThreat {
-        let mut line = Vec::new();
+#[derive(Serialize)]
+struct StoneOut {
+    x: usize,
+    y: usize,
+    color: Color,
+}
 
-        for offset in -4..=4 {
-            let nx = x as isize + offset * dx;
-            let ny = y as isize + offset * dy;
+#[derive(Serialize)]
+struct Swap2Response {
+    stones: Vec<StoneOut>,
+}
 
-            if nx < 0 || ny < 0 || nx >= BOARD_SIZE as isize || ny >= BOARD_SIZE as isize {
-                line.push('B');
-            } else {
-                let (nx, ny) = (nx as usize, ny as usize);
-                line.push(match (
-                    self.is_my_move(nx, ny),
-                    self.is_opponent_move(nx, ny),
-                ) {
-                    (true, false) if is_my => 'X',
-                    (false, true) if !is_my => 'O',
-                    (false, false) => '.',
-                    _ => 'B',
-                });
-            }
-        }
+#[derive(Serialize)]
+struct ColorResponse {
+    color: Color,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    r#move: Option<CoordOut>,
+}
 
-        let line_str: String = line.iter().collect();
-        let s = line_str.as_str();
+#[derive(Serialize)]
+struct UndoResponse {
+    undone: usize,
+    moves: usize,
+}
 
-        let (five, open4, block4, open3, block3, two) = if is_my {
-            ("XXXXX", ".XXXX.", ["XXXX.", ".XXXX", "X.XXX", "XX.XX"], ".XXX.", ["XXX.", ".XXX", "X.XX", "XX.X"], "XX")
-        } else {
-            ("OOOOO", ".OOOO.", ["OOOO.", ".OOOO", "O.OOO", "OO.OO"], ".OOO.", ["OOO.", ".OOO", "O.OO", "OO.O"], "OO")
+#[derive(Serialize)]
+struct StatsResponse {
+    moves: usize,
+    last_nodes: u64,
+    last_time_ms: u64,
+    total_time_ms: u64,
+    fill: f64,
+    opponent_model: OpponentModel,
+}
+
+// How many decisions took how long, bucketed by order of magnitude.
+#[derive(Serialize, Clone, Default)]
+struct TimingHistogram {
+    under_1ms: u64,
+    under_10ms: u64,
+    under_100ms: u64,
+    under_1s: u64,
+    over_1s: u64,
+}
+
+impl TimingHistogram {
+    fn record(&mut self, time: Duration) {
+        let bucket = match time.as_millis() {
+            0 => &mut self.under_1ms,
+            1..10 => &mut self.under_10ms,
+            10..100 => &mut self.under_100ms,
+            100..1000 => &mut self.under_1s,
+            _ => &mut self.over_1s,
         };
+        *bucket += 1;
+    }
+}
 
-        if s.contains(five) {
-            LineThreat::Five
-        } else if s.contains(open4) {
-            LineThreat::OpenFour
-        } else if block4.iter().any(|pat| s.contains(pat)) {
-            LineThreat::BlockedFour
-        } else if s.contains(open3) {
-            LineThreat::OpenThree
-        } else if block3.iter().any(|pat| s.contains(pat)) {
-            LineThreat::BlockedThree
-        } else if s.contains(two) {
-            LineThreat::Two
+// Running score of a best-of-N match; each finished game is counted, and its board cleared when
+// the next one starts.
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct MatchTally {
+    games: usize,
+    played: usize,
+    wins: usize,
+    losses: usize,
+    draws: usize,
+}
+
+// How the opponent has answered our open threes, over all the games of a match. An opponent that
+// lets them stand makes building threes pay, so our three weights move by up to MAX_THREE_BIAS
+// percent either way.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+struct OpponentModel {
+    threes_blocked: u32,
+    threes_ignored: u32,
+}
+
+impl OpponentModel {
+    fn observe(&mut self, threes_before: usize, threes_after: usize) {
+        if threes_before == 0 {
+            return;
+        }
+        if threes_after < threes_before {
+            self.threes_blocked += 1;
         } else {
-            LineThreat::Other
+            self.threes_ignored += 1;
         }
     }
 
-   fn find_best_move(&self) -> Option<(usize, usize)> {
-        let mut rng = rand::thread_rng();
-
-        for y in 0..BOARD_SIZE {
-            for x in 0..BOARD_SIZE {
-                if !self.is_empty(x, y) {
-                    continue;
-                }
-                let mut my_fours = 0;
-                let mut my_threes = 0;
-                for &(dx, dy) in &[(1, 0), (0, 1), (1, 1), (1, -1)] {
-                    match self.evaluate_line_type(x, y, dx, dy, true) {
-                        LineThreat::OpenFour => my_fours += 1,
-                        LineThreat::OpenThree => my_threes += 1,
-                        _ => {}
-                    }
-                }
-                if my_fours > 0 || my_threes >= 2 {
-                    return Some((x, y));
-                }
-            }
+    // Percent added to our three weights; none until a few answers have been seen.
+    fn three_bias(&self) -> i64 {
+        let seen = self.threes_blocked + self.threes_ignored;
+        if seen < MIN_THREES_SEEN {
+            return 0;
         }
+        (i64::from(self.threes_ignored) - i64::from(self.threes_blocked)) * MAX_THREE_BIAS / i64::from(seen)
+    }
+}
 
-        let mut best_moves = vec![];
-        let mut best_score = i32::MIN;
+#[derive(Clone, Copy)]
+enum Outcome {
+    Win,
+    Loss,
+    Draw,
+}
 
-        for y in 0..BOARD_SIZE {
-            for x in 0..BOARD_SIZE {
-                if !self.is_empty(x, y) {
-                    continue;
-                }
+#[derive(Serialize)]
+struct RemainingResponse {
+    empty: usize,
+    total: usize,
+}
 
-                let mut score = 0;
-                let mut my_open_threes = 0;
-                let mut my_open_fours = 0;
-                let mut opp_open_threes = 0;
-                let mut opp_open_fours = 0;
-
-                for &(dx, dy) in &[(1, 0), (0, 1), (1, 1), (1, -1)] {
-                    match self.evaluate_line_type(x, y, dx, dy, true) {
-                        LineThreat::Five => score += 1_000_000,
-                        LineThreat::OpenFour => { my_open_fours += 1; score += 80_000; },
-                        LineThreat::BlockedFour => score += 12_000,
-                        LineThreat::OpenThree => { my_open_threes += 1; score += 3_000; },
-                        LineThreat::BlockedThree => score += 500,
-                        LineThreat::Two => score += 100,
-                        _ => {}
-                    }
-                    match self.evaluate_line_type(x, y, dx, dy, false) {
-                        LineThreat::Five => score += 900_000,
-                        LineThreat::OpenFour => { opp_open_fours += 1; score += 55_000; },
-                        LineThreat::BlockedFour => score += 12_000,
-                        LineThreat::OpenThree => { opp_open_threes += 1; score += 3_000; },
-                        LineThreat::BlockedThree => score += 1000,
-                        LineThreat::Two => score += 200,
-                        _ => {}
-                    }
-                }
+#[derive(Serialize)]
+struct Reply {
+    reply: String,
+}
 
-                if my_open_fours > 0 && my_open_threes > 0 {
-                    score += 150_000;
-                }
-                if opp_open_fours > 0 && opp_open_threes > 0 {
-                    score += 100_000;
-                }
-                if my_open_threes >= 2 {
-                    score += 10_000;
-                }
-                if opp_open_threes >= 2 {
-                    score += 7_000;
-                }
 
-                let dist = (x as isize - CENTER as isize).abs() + (y as isize - CENTER as isize).abs();
-                score -= dist as i32;
+// Heuristic weights for score_cell, per side. `--weights <path>` loads them from JSON, and any
+// weight left out of the file keeps that side's default.
+#[derive(Clone, Serialize, Deserialize)]
+struct ThreatWeights {
+    five: i32,
+    open_four: i32,
+    gap_four: i32,
+    blocked_four: i32,
+    split: i32,
+    open_three: i32,
+    // A three with a gap, `XX.X`: one stone makes a four of it either way.
+    broken_three: i32,
+    blocked_three: i32,
+    two: i32,
+    four_three: i32,
+    double_three: i32,
+    // Two fours from one stone: only one can be blocked, so this is all but a five.
+    double_four: i32,
+}
 
-                if score > best_score {
-                    best_score = score;
-                    best_moves.clear();
-                    best_moves.push((x, y));
-                } else if score == best_score {
-                    best_moves.push((x, y));
-                }
-            }
+impl ThreatWeights {
+    fn value(&self, threat: LineThreat) -> i32 {
+        match threat {
+            LineThreat::Five => self.five,
+            LineThreat::OpenFour => self.open_four,
+            LineThreat::GapFour => self.gap_four,
+            LineThreat::BlockedFour => self.blocked_four,
+            LineThreat::Split => self.split,
+            LineThreat::OpenThree => self.open_three,
+            LineThreat::BrokenThree => self.broken_three,
+            LineThreat::BlockedThree => self.blocked_three,
+            LineThreat::Two => self.two,
+            LineThreat::Other => 0,
         }
+    }
 
-        best_moves.choose(&mut rng).copied()
+    fn smallest(&self) -> i32 {
+        [
+            self.five,
+            self.open_four,
+            self.gap_four,
+            self.blocked_four,
+            self.split,
+            self.open_three,
+            self.broken_three,
+            self.blocked_three,
+            self.two,
+            self.four_three,
+            self.double_three,
+            self.double_four,
+        ]
+        .into_iter()
+        .filter(|&weight| weight > 0)
+        .min()
+        .unwrap_or(0)
     }
 }
 
+#[derive(Clone, Serialize)]
+struct Weights {
+    mine: ThreatWeights,
+    opponent: ThreatWeights,
+}
 
+impl Default for Weights {
+    fn default() -> Self {
+        Self {
+            mine: ThreatWeights {
+                five: 1_000_000,
+                open_four: 80_000,
+                gap_four: 60_000,
+                blocked_four: 12_000,
+                split: 5_000,
+                open_three: 3_000,
+                broken_three: 1_500,
+                blocked_three: 500,
+                two: 100,
+                four_three: 150_000,
+                double_three: 10_000,
+                double_four: 800_000,
+            },
+            opponent: ThreatWeights {
+                five: 900_000,
+                open_four: 55_000,
+                gap_four: 45_000,
+                blocked_four: 12_000,
+                split: 5_000,
+                open_three: 3_000,
+                broken_three: 2_000,
+                blocked_three: 1000,
+                two: 200,
+                four_three: 100_000,
+                double_three: 7_000,
+                double_four: 700_000,
+            },
+        }
+    }
+}
 
-
-fn error(msg: &str) -> HashMap<&str, &str> {
-    HashMap::from([("error", msg)])
+// Each side in the file is laid over that side's defaults, so a file written before a weight
+// existed gets the default for the side it belongs to.
+impl<'de> Deserialize<'de> for Weights {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        type Side = serde_json::Map<String, serde_json::Value>;
+        #[derive(Deserialize)]
+        struct Sides {
+            #[serde(default)]
+            mine: Side,
+            #[serde(default)]
+            opponent: Side,
+        }
+        let overlay = |defaults: &ThreatWeights, side: Side| -> Result<ThreatWeights, D::Error> {
+            let mut weights = serde_json::to_value(defaults).map_err(serde::de::Error::custom)?;
+            if let serde_json::Value::Object(weights) = &mut weights {
+                weights.extend(side);
+            }
+            serde_json::from_value(weights).map_err(serde::de::Error::custom)
+        };
+        let sides = Sides::deserialize(deserializer)?;
+        let defaults = Weights::default();
+        Ok(Weights {
+            mine: overlay(&defaults.mine, sides.mine)?,
+            opponent: overlay(&defaults.opponent, sides.opponent)?,
+        })
+    }
 }
 
-fn handle_client(mut sock: TcpStream, state: Arc<Mutex<GameState>>) {
-    let peer = sock.peer_addr().unwrap();
-    let mut reader = BufReader::new(sock.try_clone().unwrap());
+impl Weights {
+    fn side(&self, player: Player) -> &ThreatWeights {
+        if player.is_my() { &self.mine } else { &self.opponent }
+    }
 
-    loop {
-        let mut buf = String::new();
-        if reader.read_line(&mut buf).is_err() {
-            break;
-        }
-        let line = buf.trim();
-        if line.is_empty() {
-            continue;
+    fn load(path: &str) -> io::Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    // Presets for `--style`. The defaults lean towards attacking; defensive swaps the two sides'
+    // weights so blocking pays best, and balanced values both sides' threats alike.
+    fn for_style(style: &str) -> Option<Self> {
+        let aggressive = Self::default();
+        match style {
+            "aggressive" => Some(aggressive),
+            "defensive" => Some(Self { mine: aggressive.opponent, opponent: aggressive.mine }),
+            "balanced" => Some(Self { mine: aggressive.mine.clone(), opponent: aggressive.mine }),
+            _ => None,
         }
+    }
+}
 
-        let resp_json = {
-            let mut game = state.lock().unwrap();
-            match serde_json::from_str::<Command>(line) {
-                Ok(cmd) => match cmd.command.as_str() {
-                    "start" => {
-                        if game.first_move {
-                            game.my_board[CENTER][CENTER] = true;
-                            game.first_move = false;
-                            serde_json::to_string(&MoveResponse {
-                                r#move: CoordOut::from_usize(CENTER, CENTER),
-                                team: "team crabs",
-                            })
-                            .unwrap()
-                        } else {
-                            serde_json::to_string(&error("Not first move")).unwrap()
-                        }
-                    }
-                    "move" => {
-                        if let Some(c) = cmd.opponentMove {
-                            let x = c.x.as_usize();
-                            let y = c.y.as_usize();
-
-                            if !game.is_opponent_move(x, y) {
-                                game.opponent_moves.push((x, y));
-                                game.total_moves.push((x, y));
-                            }
-                            if let Some((bx, by)) = game.find_best_move() {
-                                if game.is_empty(bx, by) {
-                                    game.my_board[by][bx] = true;
-                                    game.total_moves.push((bx, by));
-                                    serde_json::to_string(&MoveResponse {
-                                        r#move: CoordOut::from_usize(bx, by),
-                                        team: "team crabs",
-                                    })
-                                    .unwrap()
-                                } else {
-                                    serde_json::to_string(&error("Move already taken")).unwrap()
-                                }
-                            } else {
-                                serde_json::to_string(&error("No valid move found")).unwrap()
-                            }
-                        } else {
-                            serde_json::to_string(&error("No opponent move")).unwrap()
-                        }
-                    }
-                    "reset" => {
-                        game.reset();
-                        serde_json::to_string(&Reply { reply: "ok".into() }).unwrap()
-                    }
-                    _ => serde_json::to_string(&error("Unknown command")).unwrap(),
-                },
-                Err(_) => serde_json::to_string(&error("Wrong JSON format")).unwrap(),
-            }
-        } + "\n";
+// Opening book from `--book <path>`: a JSON list of lines, each the moves played so far, in order,
+// and the reply to make there. A line also matches any mirror image or rotation of the game.
+#[derive(Deserialize)]
+struct BookLine {
+    moves: Vec<(usize, usize)>,
+    reply: (usize, usize),
+}
 
-        if sock.write_all(resp_json.as_bytes()).is_err() {
-            eprintln!("Lost connection to {}", peer);
-            break;
-        }
+#[derive(Default, Deserialize)]
+#[serde(transparent)]
+struct Book(Vec<BookLine>);
+
+impl Book {
+    fn load(path: &str) -> io::Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
     }
 }
 
-fn main() -> std::io::Result<()> {
-    let port = env::args()
-        .find_map(|arg| arg.strip_prefix("-p")?.parse::<u16>().ok())
-        .unwrap_or(54321);
-    let addr = format!("0.0.0.0:{}", port);
+// A client-supplied name cut down to characters that are safe in a file name, so it can't leave
+// the directory it is joined to.
+fn file_name(name: &str) -> String {
+    name.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_').collect()
+}
 
-    let listener = TcpListener::bind(&addr)?;
-    println!("Server running on {}", addr);
+type SessionId = String;
+// A session's game and when a command last used it.
+type Session = (Arc<Mutex<GameState>>, Instant);
 
-    let state = Arc::new(Mutex::new(GameState::new()));
-    for stream in listener.incoming() {
-        match stream {
-            Ok(sock) => {
-                let st = Arc::clone(&state);
-                thread::spawn(move || handle_client(sock, st));
-            }
-            Err(e) => eprintln!("Connection error: {}", e),
+// Commands without a session id share the default session, as all clients did before.
+struct Server {
+    template: GameState,
+    team: String,
+    gtp: bool,
+    client_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    workers: Option<usize>,
+    framing: Framing,
+    // Commands per second allowed from one IP, and when each client IP sent its recent ones.
+    rate: Option<usize>,
+    requests: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+    // Sessions left idle this long are dropped; their next command starts a fresh game.
+    session_ttl: Option<Duration>,
+    sessions: Mutex<HashMap<SessionId, Session>>,
+}
+
+impl Server {
+    fn new(template: GameState) -> Self {
+        Self {
+            template,
+            team: "team crabs".into(),
+            gtp: false,
+            client_timeout: None,
+            max_connections: None,
+            workers: None,
+            framing: Framing::Newline,
+            rate: None,
+            requests: Mutex::new(HashMap::new()),
+            session_ttl: None,
+            sessions: Mutex::new(HashMap::new()),
         }
     }
-    Ok(())
+
+    // An error outside any one command, as a reply in the server's protocol.
+    fn error_reply(&self, code: ErrorCode, msg: &str) -> String {
+        if self.gtp { format!("? {}\n", msg.to_lowercase()) } else { respond(&error(code, msg)) }
+    }
+
+    // Whether a command from `ip` goes over the rate, counting those it sent in the last second.
+    // Rejected commands don't count, so a client that backs off gets through again.
+    fn rate_limited(&self, ip: IpAddr) -> bool {
+        let Some(rate) = self.rate else { return false };
+        let now = Instant::now();
+        let mut requests = lock(&self.requests);
+        let recent = requests.entry(ip).or_default();
+        while recent.front().is_some_and(|&sent| now.duration_since(sent) >= Duration::from_secs(1)) {
+            recent.pop_front();
+        }
+        if recent.len() >= rate {
+            return true;
+        }
+        recent.push_back(now);
+        false
+    }
+
+    fn snapshot(&self, dir: &str) {
+        let sessions = lock(&self.sessions);
+        for (id, (state, _)) in sessions.iter() {
+            let name = file_name(id);
+            let path = format!("{}/{}.json", dir, if name.is_empty() { "default" } else { &name });
+            if let Err(e) = lock(state).save(&path) {
+                log!(Error, "Could not snapshot session to {}: {}", path, e);
+            }
+        }
+    }
+
+    fn session(&self, id: &str) -> Arc<Mutex<GameState>> {
+        let mut sessions = lock(&self.sessions);
+        let now = Instant::now();
+        // Expired sessions are swept here, as commands come in, rather than on a timer.
+        if let Some(ttl) = self.session_ttl {
+            sessions.retain(|id, (_, last_used)| {
+                let live = now.duration_since(*last_used) < ttl;
+                if !live {
+                    log!(Info, "Session {:?} expired", id);
+                }
+                live
+            });
+        }
+        let (state, last_used) = sessions
+            .entry(id.to_string())
+            .or_insert_with(|| (Arc::new(Mutex::new(self.template.clone())), now));
+        *last_used = now;
+        Arc::clone(state)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct GameState {
+    width: usize,
+    height: usize,
+    center: (usize, usize),
+    // Bitboards with one u64 per row and bit x for column x; MAX_BOARD_SIZE keeps rows within a
+    // word. They are rebuilt from the history, so only the history is saved.
+    #[serde(skip)]
+    my_bits: Vec<u64>,
+    #[serde(skip)]
+    opponent_bits: Vec<u64>,
+    first_move: bool, 
+    history: Vec<(u16, u16, Player)>,
+    my_color: Color,
+    opening: Vec<(usize, usize, Color)>,
+    search_depth: u32,
+    time_budget: Duration,
+    radius: usize,
+    // With a threshold, the search looks one step out first and widens the scan, up to `radius`,
+    // while no candidate scores at least this much.
+    #[serde(default)]
+    expand_below: Option<i32>,
+    max_moves: Option<usize>,
+    // Whether six or more in a row wins; when false only an exact five does.
+    allow_overline: bool,
+    // Points per step of distance from the center in score_cell.
+    #[serde(default = "default_center_bias")]
+    center_bias: i32,
+    #[serde(default)]
+    match_tally: Option<MatchTally>,
+    // A match game has ended and been counted. Its final position stays until the next game starts.
+    #[serde(default)]
+    game_over: bool,
+    #[serde(default)]
+    opponent_model: OpponentModel,
+    // Pente rules: a pair of stones flanked on both ends by the mover is captured. Only played
+    // moves capture; the search looks ahead without them.
+    #[serde(default)]
+    pente: bool,
+    // Renju rules: black may not make a double three, a double four or an overline.
+    #[serde(default)]
+    renju: bool,
+    weights: Weights,
+    #[serde(skip)]
+    book: Arc<Book>,
+    // Where save and load keep their files, named by the client; without it both are refused.
+    #[serde(skip)]
+    state_dir: Option<String>,
+    #[serde(skip)]
+    deadline: Option<Instant>,
+    #[serde(skip)]
+    timed_out: bool,
+    #[serde(skip)]
+    zobrist: u64,
+    // The hash of the position under each board symmetry (see transform), for canonical_hash.
+    #[serde(skip)]
+    symmetric_zobrist: [u64; 8],
+    #[serde(skip)]
+    board_score: i64,
+    #[serde(skip)]
+    transpositions: HashMap<u64, (i32, u32)>,
+    // Best move found for each searched position, followed to recover the principal variation.
+    #[serde(skip)]
+    best_replies: HashMap<u64, (usize, usize)>,
+    #[serde(skip)]
+    killers: [[Option<(usize, usize)>; 2]; MAX_DEPTH],
+    // Off, the search keeps to score order and records no killers; only useful for comparison.
+    #[serde(skip, default = "default_use_killers")]
+    use_killers: bool,
+    #[serde(skip)]
+    nodes: u64,
+    // Search extensions taken along the line being searched.
+    #[serde(skip)]
+    extensions: u32,
+    // Logs each search decision to stderr.
+    #[serde(skip)]
+    verbose: bool,
+    // Draws the board on stderr after each move.
+    #[serde(skip)]
+    print_board: bool,
+    // Adds the search depth reached to each move reply.
+    #[serde(skip)]
+    report_depth: bool,
+    #[serde(skip)]
+    last_nodes: u64,
+    #[serde(skip)]
+    last_think: Duration,
+    #[serde(skip)]
+    total_think: Duration,
+    #[serde(skip)]
+    think_times: TimingHistogram,
+    // Breaks ties between equally good moves; seeding it makes games reproducible.
+    #[serde(skip, default = "StdRng::from_entropy")]
+    rng: StdRng,
+    // Breaks ties by scan order (lowest y, then x) instead, for byte-identical replays.
+    #[serde(default)]
+    deterministic: bool,
+    // Among tied moves, keeps to those in line with our last stone, so play follows one plan.
+    #[serde(default)]
+    follow_plan: bool,
+    #[serde(default)]
+    strategy: Strategy,
+}
+
+// A run of stones: whose, along which direction, and which cells.
+type RunKey = (Player, (isize, isize), Vec<(usize, usize)>);
+
+// Strongest first, which is the order they compare in.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum LineThreat {
+    Five,
+    OpenFour,
+    GapFour,
+    BlockedFour,
+    Split,
+    OpenThree,
+    BrokenThree,
+    BlockedThree,
+    Two,
+    Other,
+}
+
+
+
+impl GameState {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            center: (width / 2, height / 2),
+            my_bits: vec![0; height],
+            opponent_bits: vec![0; height],
+            first_move: true,
+            history: Vec::new(),
+            my_color: Color::Black,
+            opening: Vec::new(),
+            search_depth: DEFAULT_DEPTH,
+            time_budget: Duration::from_millis(DEFAULT_TIME_BUDGET_MS),
+            radius: DEFAULT_RADIUS,
+            expand_below: None,
+            max_moves: None,
+            allow_overline: true,
+            center_bias: DEFAULT_CENTER_BIAS,
+            pente: false,
+            renju: false,
+            match_tally: None,
+            game_over: false,
+            opponent_model: OpponentModel::default(),
+            weights: Weights::default(),
+            book: Arc::default(),
+            state_dir: None,
+            deadline: None,
+            timed_out: false,
+            zobrist: 0,
+            symmetric_zobrist: [0; 8],
+            board_score: 0,
+            transpositions: HashMap::new(),
+            best_replies: HashMap::new(),
+            killers: Default::default(),
+            use_killers: true,
+            nodes: 0,
+            extensions: 0,
+            verbose: false,
+            print_board: false,
+            report_depth: false,
+            last_nodes: 0,
+            last_think: Duration::ZERO,
+            total_think: Duration::ZERO,
+            think_times: TimingHistogram::default(),
+            rng: StdRng::from_entropy(),
+            deterministic: false,
+            follow_plan: false,
+            strategy: Strategy::Heuristic,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.my_bits.fill(0);
+        self.opponent_bits.fill(0);
+        self.first_move = true;
+        self.history.clear();
+        self.my_color = Color::Black;
+        self.opening.clear();
+        self.zobrist = 0;
+        self.symmetric_zobrist = [0; 8];
+        self.board_score = 0;
+        self.transpositions.clear();
+        self.best_replies.clear();
+        self.last_nodes = 0;
+        self.last_think = Duration::ZERO;
+        self.total_think = Duration::ZERO;
+        self.think_times = TimingHistogram::default();
+        self.opponent_model = OpponentModel::default();
+        self.game_over = false;
+    }
+
+    // The next game of a match: a fresh board, but what we've learned about the opponent carries over.
+    fn next_game(&mut self) {
+        let model = self.opponent_model;
+        self.reset();
+        self.opponent_model = model;
+    }
+
+    // Counts a finished game towards the match, if one is running. The final position stays on the
+    // board either way, so a client resending its last move still gets the same answer.
+    fn finish_game(&mut self, outcome: Outcome) {
+        let Some(tally) = self.match_tally.as_mut().filter(|tally| tally.played < tally.games) else {
+            return;
+        };
+        tally.played += 1;
+        match outcome {
+            Outcome::Win => tally.wins += 1,
+            Outcome::Loss => tally.losses += 1,
+            Outcome::Draw => tally.draws += 1,
+        }
+        self.game_over = true;
+    }
+
+    // The file a save or load named `name` uses, if there is a state directory and the name has
+    // anything left once sanitized.
+    fn state_path(&self, name: &str) -> Option<String> {
+        let name = file_name(name);
+        let dir = self.state_dir.as_ref().filter(|_| !name.is_empty())?;
+        Some(format!("{}/{}.json", dir, name))
+    }
+
+    fn save(&self, path: &str) -> io::Result<()> {
+        fs::write(path, serde_json::to_string(self)?)
+    }
+
+    fn load(path: &str) -> io::Result<Self> {
+        let mut state: GameState = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if !state.is_consistent() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "inconsistent game state"));
+        }
+        state.rebuild();
+        Ok(state)
+    }
+
+    fn is_consistent(&self) -> bool {
+        let mut seen = HashSet::new();
+        (MIN_BOARD_SIZE..=MAX_BOARD_SIZE).contains(&self.width)
+            && (MIN_BOARD_SIZE..=MAX_BOARD_SIZE).contains(&self.height)
+            && self.center == (self.width / 2, self.height / 2)
+            && self
+                .history
+                .iter()
+                .all(|&(x, y, _)| self.in_bounds(x as usize, y as usize) && seen.insert((x, y)))
+            && self.opening.iter().all(|&(x, y, _)| self.in_bounds(x, y))
+    }
+
+    // Replays the history onto cleared bitboards.
+    fn rebuild(&mut self) {
+        self.my_bits = vec![0; self.height];
+        self.opponent_bits = vec![0; self.height];
+        self.zobrist = 0;
+        self.symmetric_zobrist = [0; 8];
+        let history = std::mem::take(&mut self.history);
+        for &(x, y, player) in &history {
+            let (x, y) = (x as usize, y as usize);
+            self.bits_mut(player)[y] |= 1 << x;
+            self.toggle_zobrist(x, y, player);
+        }
+        self.history = history;
+        self.board_score = self.full_rescore();
+    }
+
+    // Takes the pairs that the stone just played at (x, y) flanks, in any of the eight directions,
+    // off the board and out of the history. Returns the captured cells.
+    fn capture(&mut self, x: usize, y: usize, player: Player) -> Vec<(usize, usize)> {
+        if !self.pente {
+            return vec![];
+        }
+        let (mover, enemy) = (player, player.other());
+        let mut captured = vec![];
+        for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+            let cell = |k: isize| (x.wrapping_add_signed(k * dx), y.wrapping_add_signed(k * dy));
+            let [a, b, c] = [cell(1), cell(2), cell(3)];
+            if self.in_bounds(c.0, c.1)
+                && self.stone_at(a.0, a.1) == Some(enemy)
+                && self.stone_at(b.0, b.1) == Some(enemy)
+                && self.stone_at(c.0, c.1) == Some(mover)
+            {
+                captured.extend([a, b]);
+            }
+        }
+        if !captured.is_empty() {
+            self.history.retain(|&(hx, hy, _)| !captured.contains(&(hx as usize, hy as usize)));
+            self.rebuild();
+        }
+        captured
+    }
+
+    // Swap2: we lay out the opening, and the stones stay unassigned until colors are settled.
+    fn propose_swap2(&mut self) -> &[(usize, usize, Color)] {
+        let (cx, cy) = self.center;
+        self.first_move = false;
+        self.opening = vec![(cx, cy, Color::Black), (cx + 1, cy + 1, Color::White), (cx + 1, cy - 1, Color::Black)];
+        &self.opening
+    }
+
+    fn decide_swap2(&mut self, choice: &str, extra: &[(usize, usize)]) -> Result<Color, &'static str> {
+        if self.opening.is_empty() {
+            return Err("No Swap2 opening to decide");
+        }
+        let my_color = match choice {
+            "black" => Color::White,
+            "white" => Color::Black,
+            "place2" => {
+                let &[black, white] = extra else {
+                    return Err("Expected two stones");
+                };
+                let taken = |(x, y): (usize, usize)| self.opening.iter().any(|&(ox, oy, _)| (ox, oy) == (x, y));
+                if black == white
+                    || [black, white]
+                        .iter()
+                        .any(|&(x, y)| !self.in_bounds(x, y) || !self.is_empty(x, y) || taken((x, y)))
+                {
+                    return Err("Invalid stone placement");
+                }
+                self.opening.push((black.0, black.1, Color::Black));
+                self.opening.push((white.0, white.1, Color::White));
+                self.choose_swap2_color()
+            }
+            _ => return Err("Unknown Swap2 choice"),
+        };
+        self.commit_opening(my_color);
+        Ok(my_color)
+    }
+
+    // White has the move after the opening, so it takes any position black doesn't lead.
+    fn choose_swap2_color(&self) -> Color {
+        let mut as_black = self.clone();
+        as_black.commit_opening(Color::Black);
+        if as_black.static_score() > 0 { Color::Black } else { Color::White }
+    }
+
+    fn commit_opening(&mut self, my_color: Color) {
+        self.my_color = my_color;
+        for (x, y, color) in std::mem::take(&mut self.opening) {
+            self.place_stone(x, y, Player::from_is_my(color == my_color));
+        }
+    }
+
+    // Stones keep their colors; switching sides only changes which of them count as ours.
+    fn set_my_color(&mut self, color: Color) {
+        if color == self.my_color {
+            return;
+        }
+        self.my_color = color;
+        for (_, _, player) in &mut self.history {
+            *player = player.other();
+        }
+        self.rebuild();
+    }
+
+    // Replays a game onto a fresh board. On the first move that is out of bounds or on an occupied
+    // cell it stops and returns that move's index.
+    fn load_game(&mut self, moves: &[(usize, usize, Player)]) -> Result<(), usize> {
+        self.reset();
+        for (i, &(x, y, player)) in moves.iter().enumerate() {
+            if !self.in_bounds(x, y) || !self.is_empty(x, y) {
+                return Err(i);
+            }
+            self.place_stone(x, y, player);
+        }
+        if let Some(&(_, _, first)) = moves.first() {
+            self.first_move = false;
+            self.my_color = if first.is_my() { Color::Black } else { Color::White };
+        }
+        Ok(())
+    }
+
+    fn in_bounds(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height
+    }
+
+    fn bits(&self, player: Player) -> &[u64] {
+        match player {
+            Player::Me => &self.my_bits,
+            Player::Opponent => &self.opponent_bits,
+        }
+    }
+
+    fn bits_mut(&mut self, player: Player) -> &mut [u64] {
+        match player {
+            Player::Me => &mut self.my_bits,
+            Player::Opponent => &mut self.opponent_bits,
+        }
+    }
+
+    fn is_my_move(&self, x: usize, y: usize) -> bool {
+        self.my_bits[y] >> x & 1 != 0
+    }
+
+    fn is_opponent_move(&self, x: usize, y: usize) -> bool {
+        self.opponent_bits[y] >> x & 1 != 0
+    }
+
+    fn stone_at(&self, x: usize, y: usize) -> Option<Player> {
+        if self.is_my_move(x, y) {
+            Some(Player::Me)
+        } else if self.is_opponent_move(x, y) {
+            Some(Player::Opponent)
+        } else {
+            None
+        }
+    }
+
+    fn stones(&self, player: Player) -> Vec<(usize, usize)> {
+        let mut stones = vec![];
+        for (y, &row) in self.bits(player).iter().enumerate() {
+            let mut row = row;
+            while row != 0 {
+                stones.push((row.trailing_zeros() as usize, y));
+                row &= row - 1;
+            }
+        }
+        stones
+    }
+
+    // ASCII board with column and row numbers: X for us, O for the opponent. Boards wider or taller
+    // than MAX_RENDER are cropped to the stones played, plus a margin of two.
+    fn render(&self) -> String {
+        let (mut x0, mut x1, mut y0, mut y1) = (0, self.width - 1, 0, self.height - 1);
+        if (self.width > MAX_RENDER || self.height > MAX_RENDER) && !self.history.is_empty() {
+            let xs = self.history.iter().map(|&(x, _, _)| x as usize);
+            let ys = self.history.iter().map(|&(_, y, _)| y as usize);
+            x0 = xs.clone().min().unwrap().saturating_sub(2);
+            x1 = (xs.max().unwrap() + 2).min(self.width - 1);
+            y0 = ys.clone().min().unwrap().saturating_sub(2);
+            y1 = (ys.max().unwrap() + 2).min(self.height - 1);
+        }
+
+        let mut out = String::from("   ");
+        for x in x0..=x1 {
+            out += &format!("{:>3}", x);
+        }
+        out.push('\n');
+        for y in y0..=y1 {
+            out += &format!("{:>3}", y);
+            for x in x0..=x1 {
+                let cell = self.stone_at(x, y).map_or('.', Player::stone);
+                out += &format!("{:>3}", cell);
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn is_empty(&self, x: usize, y: usize) -> bool {
+        !self.is_my_move(x, y) && !self.is_opponent_move(x, y)
+    }
+
+    // The same reading as classify_line over line_window, looked up by the window's code instead.
+    #[inline]
+    fn evaluate_line_type(&self, x: usize, y: usize, dx: isize, dy: isize, player: Player) -> LineThreat {
+        let (lo_x, hi_x) = Self::offset_range(x, self.width, dx);
+        let (lo_y, hi_y) = Self::offset_range(y, self.height, dy);
+        let on_board = lo_x.max(lo_y)..=hi_x.min(hi_y);
+        let mut code = 0;
+        for offset in (-4..=4).rev() {
+            let (nx, ny) = (x.wrapping_add_signed(offset * dx), y.wrapping_add_signed(offset * dy));
+            let digit = match on_board.contains(&offset).then(|| self.stone_at(nx, ny)) {
+                Some(None) => 0,
+                Some(Some(stone)) if stone == player => 1,
+                _ => 2,
+            };
+            code = code * 3 + digit;
+        }
+        line_threat(code)
+    }
+
+    #[inline]
+    fn line_window(&self, x: usize, y: usize, dx: isize, dy: isize, player: Player) -> Vec<char> {
+        // Only the offsets that stay on the board along both axes are visited; the rest of the
+        // window is the edge, so no index outside the board is ever built.
+        let (lo_x, hi_x) = Self::offset_range(x, self.width, dx);
+        let (lo_y, hi_y) = Self::offset_range(y, self.height, dy);
+        let mut line = vec!['B'; 9];
+
+        for offset in lo_x.max(lo_y)..=hi_x.min(hi_y) {
+            let (nx, ny) = (x.wrapping_add_signed(offset * dx), y.wrapping_add_signed(offset * dy));
+            line[(offset + 4) as usize] = match self.stone_at(nx, ny) {
+                None => '.',
+                Some(stone) if stone == player => player.stone(),
+                // The other side's stones block a run exactly like the edge of the board.
+                Some(_) => 'B',
+            };
+        }
+        line
+    }
+
+    // The offsets in -4..=4 that keep `pos + offset * d` inside 0..len.
+    #[inline]
+    fn offset_range(pos: usize, len: usize, d: isize) -> (isize, isize) {
+        let (behind, ahead) = (pos as isize, len as isize - 1 - pos as isize);
+        match d {
+            0 => (-4, 4),
+            1 => (-behind.min(4), ahead.min(4)),
+            _ => (-ahead.min(4), behind.min(4)),
+        }
+    }
+
+    fn classify_line(line: &[char], player: Player) -> LineThreat {
+        // The patterns are written for X; the window only ever holds one side's stones.
+        let line_str: String = line.iter().map(|&c| if c == player.stone() { 'X' } else { c }).collect();
+        let s = line_str.as_str();
+
+        // A three is only open if it can still grow into an open four, so `B.XXX.B` is blocked.
+        // Split shapes have gaps that each turn into a four, which makes them worth more than a
+        // solid open three. A gap four is told apart from a four blocked at one end: its walls
+        // don't matter, and the gap is the one cell that has to be answered. A blocked three with a
+        // gap is worth more than a solid one, as filling the gap already makes a four.
+        let (five, open4, two) = ("XXXXX", ".XXXX.", "XX");
+        let gap4 = ["X.XXX", "XXX.X", "XX.XX"];
+        let block4 = ["XXXX.", ".XXXX"];
+        let split = ["X.X.X", ".XX.X.", ".X.XX.", "XX..XX"];
+        let open3 = ["..XXX.", ".XXX.."];
+        let broken3 = ["X.XX", "XX.X"];
+        let block3 = ["XXX.", ".XXX"];
+
+        if s.contains(five) {
+            LineThreat::Five
+        } else if s.contains(open4) {
+            LineThreat::OpenFour
+        } else if gap4.iter().any(|pat| s.contains(pat)) {
+            LineThreat::GapFour
+        } else if block4.iter().any(|pat| s.contains(pat)) {
+            LineThreat::BlockedFour
+        } else if split.iter().any(|pat| s.contains(pat)) {
+            LineThreat::Split
+        } else if open3.iter().any(|pat| s.contains(pat)) {
+            LineThreat::OpenThree
+        } else if broken3.iter().any(|pat| s.contains(pat)) {
+            LineThreat::BrokenThree
+        } else if block3.iter().any(|pat| s.contains(pat)) {
+            LineThreat::BlockedThree
+        } else if s.contains(two) {
+            LineThreat::Two
+        } else {
+            LineThreat::Other
+        }
+    }
+
+    fn place_stone(&mut self, x: usize, y: usize, player: Player) {
+        let before = self.lines_score(x, y);
+        self.bits_mut(player)[y] |= 1 << x;
+        self.history.push((x as u16, y as u16, player));
+        self.toggle_zobrist(x, y, player);
+        self.board_score += self.lines_score(x, y) - before;
+    }
+
+    fn toggle_zobrist(&mut self, x: usize, y: usize, player: Player) {
+        self.zobrist ^= zobrist_key(x, y, player);
+        for symmetry in 0..self.symmetry_count() {
+            let (tx, ty) = self.transform(symmetry, x, y);
+            self.symmetric_zobrist[symmetry] ^= zobrist_key(tx, ty, player);
+        }
+    }
+
+    // The same for a position and all its reflections and rotations, so the transposition table
+    // shares one entry between them. Best replies are cells, which don't carry over, and stay keyed
+    // by the plain hash.
+    fn canonical_hash(&self) -> u64 {
+        self.symmetric_zobrist[..self.symmetry_count()].iter().copied().min().unwrap_or(self.zobrist)
+    }
+
+    fn undo_stone(&mut self) {
+        if let Some((x, y, player)) = self.history.pop() {
+            let (x, y) = (x as usize, y as usize);
+            let before = self.lines_score(x, y);
+            self.bits_mut(player)[y] &= !(1 << x);
+            self.toggle_zobrist(x, y, player);
+            self.board_score += self.lines_score(x, y) - before;
+        }
+    }
+
+    fn undo(&mut self, plies: usize) -> usize {
+        let plies = plies.min(self.history.len());
+        for _ in 0..plies {
+            self.undo_stone();
+        }
+        if self.history.is_empty() && self.opening.is_empty() {
+            self.first_move = true;
+        }
+        plies
+    }
+
+    // A full board, or the configured move cap, ends the game without a winner.
+    fn is_draw(&self) -> bool {
+        let moves = self.history.len();
+        moves >= self.width * self.height || self.max_moves.is_some_and(|cap| moves >= cap)
+    }
+
+    // Whether a stone at (x, y) completes five, or has just completed it if it's already there:
+    // only the runs either side of the cell are counted. The one place the overline rule is read.
+    fn completes_five(&self, x: usize, y: usize, player: Player) -> bool {
+        let exact = !self.allow_overline || self.is_renju_black(player);
+        DIRECTIONS.iter().any(|&(dx, dy)| {
+            let run = 1 + self.run_from(x, y, dx, dy, player) + self.run_from(x, y, -dx, -dy, player);
+            if exact { run == 5 } else { run >= 5 }
+        })
+    }
+
+    fn is_renju_black(&self, player: Player) -> bool {
+        self.renju && player.is_my() == (self.my_color == Color::Black)
+    }
+
+    // Whether a stone at the empty cell (x, y) breaks the renju rules for black. An exact five
+    // wins outright, whatever else the stone makes along the other lines.
+    fn is_forbidden(&self, x: usize, y: usize, player: Player) -> bool {
+        if !self.is_renju_black(player) || self.completes_five(x, y, player) {
+            return false;
+        }
+        let (mut fours, mut threes) = (0, 0);
+        for &(dx, dy) in &DIRECTIONS {
+            if 1 + self.run_from(x, y, dx, dy, player) + self.run_from(x, y, -dx, -dy, player) > 5 {
+                return true;
+            }
+            match self.threat_if_placed(x, y, dx, dy, player) {
+                LineThreat::OpenFour | LineThreat::GapFour | LineThreat::BlockedFour => fours += 1,
+                LineThreat::OpenThree | LineThreat::Split => threes += 1,
+                _ => {}
+            }
+        }
+        fours >= 2 || threes >= 2
+    }
+
+    // Whether a stone for the given side may go on (x, y), and if not, why.
+    fn move_legality(&self, x: usize, y: usize, player: Player) -> Result<(), (ErrorCode, &'static str)> {
+        if !self.in_bounds(x, y) {
+            Err((ErrorCode::OutOfBounds, "Coordinate out of bounds"))
+        } else if !self.is_empty(x, y) {
+            Err((ErrorCode::Occupied, "Cell is occupied"))
+        } else if self.is_forbidden(x, y, player) {
+            Err((ErrorCode::Forbidden, "Forbidden by renju rules"))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Consecutive stones of one side starting next to (x, y), going in direction (dx, dy). Unlike
+    // the 9-cell window this isn't capped, which is what tells an overline from a five.
+    fn run_from(&self, x: usize, y: usize, dx: isize, dy: isize, player: Player) -> usize {
+        let mut run = 0;
+        let (mut cx, mut cy) = (x.wrapping_add_signed(dx), y.wrapping_add_signed(dy));
+        while self.in_bounds(cx, cy) && self.stone_at(cx, cy) == Some(player) {
+            run += 1;
+            (cx, cy) = (cx.wrapping_add_signed(dx), cy.wrapping_add_signed(dy));
+        }
+        run
+    }
+
+    // The side's stones making up the run through (x, y): neighbours along the line and across
+    // single gaps, however far that goes, so every stone of a run gives the same cells.
+    fn run_cells(&self, x: usize, y: usize, dx: isize, dy: isize, player: Player) -> Vec<(usize, usize)> {
+        let mut cells = vec![(x, y)];
+        for sign in [-1, 1] {
+            let mut gap = false;
+            for step in 1.. {
+                let (cx, cy) = (x.wrapping_add_signed(sign * step * dx), y.wrapping_add_signed(sign * step * dy));
+                if !self.in_bounds(cx, cy) {
+                    break;
+                }
+                match self.stone_at(cx, cy) {
+                    Some(stone) if stone == player => {
+                        cells.push((cx, cy));
+                        gap = false;
+                    }
+                    None if !gap => gap = true,
+                    _ => break,
+                }
+            }
+        }
+        cells.sort_unstable();
+        cells
+    }
+
+    // Every two or better on the board as it stands, once per run: stones of the same run see it
+    // through different windows, and the strongest reading is the one kept.
+    fn runs(&self) -> Vec<(RunKey, LineThreat)> {
+        let mut found: Vec<(RunKey, LineThreat)> = vec![];
+        for &(x, y, player) in &self.history {
+            let (x, y) = (x as usize, y as usize);
+            for &(dx, dy) in &DIRECTIONS {
+                let threat = self.evaluate_line_type(x, y, dx, dy, player);
+                if threat == LineThreat::Other {
+                    continue;
+                }
+                let key = (player, (dx, dy), self.run_cells(x, y, dx, dy, player));
+                match found.iter_mut().find(|(run, _)| *run == key) {
+                    Some((_, best)) if threat < *best => *best = threat,
+                    Some(_) => {}
+                    None => found.push((key, threat)),
+                }
+            }
+        }
+        found
+    }
+
+    // Every three or better, as the threats command lists them.
+    fn list_threats(&self) -> Vec<ThreatInfo> {
+        self.runs()
+            .into_iter()
+            .filter(|(_, threat)| *threat != LineThreat::Two)
+            .map(|((player, _, cells), threat)| ThreatInfo {
+                player,
+                threat,
+                cells: cells.into_iter().map(|(x, y)| CoordOut::from_usize(x, y)).collect(),
+            })
+            .collect()
+    }
+
+    fn open_threes(&self, player: Player) -> usize {
+        self.list_threats()
+            .iter()
+            .filter(|info| info.player == player && matches!(info.threat, LineThreat::OpenThree | LineThreat::Split))
+            .count()
+    }
+
+    fn threat_if_placed(&self, x: usize, y: usize, dx: isize, dy: isize, player: Player) -> LineThreat {
+        let mut line = self.line_window(x, y, dx, dy, player);
+        line[4] = player.stone();
+        Self::classify_line(&line, player)
+    }
+
+    // Empty cells where one opponent stone would make two threes at once (or a four and a three).
+    // A single three can be answered when it appears; a fork cannot, so it has to be taken first.
+    fn opponent_fork_cells(&self) -> Vec<(usize, usize)> {
+        let mut forks = vec![];
+        for (x, y) in self.candidate_cells() {
+            let mut fours = 0;
+            let mut threes = 0;
+            for &(dx, dy) in &DIRECTIONS {
+                match self.threat_if_placed(x, y, dx, dy, Player::Opponent) {
+                    LineThreat::OpenFour | LineThreat::GapFour | LineThreat::BlockedFour => fours += 1,
+                    LineThreat::OpenThree | LineThreat::Split => threes += 1,
+                    _ => {}
+                }
+            }
+            if threes >= 2 || (fours > 0 && threes > 0) {
+                forks.push((x, y));
+            }
+        }
+        forks
+    }
+
+    fn winning_move(&self, player: Player) -> Option<(usize, usize)> {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                // A completing cell always touches the run it completes.
+                if self.is_empty(x, y) && self.has_neighbor(x, y, 1) && self.completes_five(x, y, player) {
+                    return Some((x, y));
+                }
+            }
+        }
+        None
+    }
+
+    fn makes_four(&self, x: usize, y: usize, player: Player) -> bool {
+        DIRECTIONS.iter().any(|&(dx, dy)| {
+            matches!(
+                self.evaluate_line_type(x, y, dx, dy, player),
+                LineThreat::OpenFour | LineThreat::GapFour | LineThreat::BlockedFour
+            )
+        })
+    }
+
+    // Victory by continuous fours: every attacking move makes a four, so the defender's reply is
+    // forced. Returns the attacker/defender sequence ending in the winning five.
+    fn vcf_search(&self, player: Player, depth: u32) -> Option<Vec<(usize, usize)>> {
+        let mut board = self.clone();
+        let mut sequence = vec![];
+        board.vcf_step(player, depth, &mut sequence).then_some(sequence)
+    }
+
+    fn vcf_step(&mut self, player: Player, depth: u32, sequence: &mut Vec<(usize, usize)>) -> bool {
+        if let Some(cell) = self.winning_move(player) {
+            sequence.push(cell);
+            return true;
+        }
+        if depth == 0 || self.past_deadline() || self.winning_move(player.other()).is_some() {
+            return false;
+        }
+
+        let mut fours = vec![];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.is_empty(x, y) && self.has_neighbor(x, y, 2) {
+                    fours.push((x, y));
+                }
+            }
+        }
+
+        for (x, y) in fours {
+            self.place_stone(x, y, player);
+            if self.makes_four(x, y, player)
+                && let Some((bx, by)) = self.winning_move(player)
+            {
+                self.place_stone(bx, by, player.other());
+                sequence.extend([(x, y), (bx, by)]);
+                if !self.completes_five(bx, by, player.other()) && self.vcf_step(player, depth - 1, sequence) {
+                    return true;
+                }
+                sequence.truncate(sequence.len() - 2);
+                self.undo_stone();
+            }
+            self.undo_stone();
+        }
+        false
+    }
+
+    // Replies that stop the threat the stone at (x, y) has just made: the completion point of a
+    // four, or any cell on the line that downgrades an open three. Empty if it threatens nothing.
+    fn threat_defenses(&self, x: usize, y: usize, player: Player) -> Vec<(usize, usize)> {
+        if self.makes_four(x, y, player) {
+            return self.winning_move(player).into_iter().collect();
+        }
+        let mut defenses = vec![];
+        for &(dx, dy) in &DIRECTIONS {
+            if !matches!(self.evaluate_line_type(x, y, dx, dy, player), LineThreat::OpenThree | LineThreat::Split) {
+                continue;
+            }
+            for offset in -4..=4 {
+                let (cx, cy) = (x.wrapping_add_signed(offset * dx), y.wrapping_add_signed(offset * dy));
+                if !self.in_bounds(cx, cy) || !self.is_empty(cx, cy) || defenses.contains(&(cx, cy)) {
+                    continue;
+                }
+                let mut line = self.line_window(x, y, dx, dy, player);
+                line[(offset + 4) as usize] = 'B';
+                if matches!(
+                    Self::classify_line(&line, player),
+                    LineThreat::Two | LineThreat::BrokenThree | LineThreat::BlockedThree | LineThreat::Other
+                ) {
+                    defenses.push((cx, cy));
+                }
+            }
+        }
+        defenses
+    }
+
+    // Fours and threes our stone at the empty cell (x, y) would make, fours first so they compare
+    // ahead of any number of threes.
+    fn threats_made(&self, x: usize, y: usize) -> (usize, usize) {
+        let (mut fours, mut threes) = (0, 0);
+        for &(dx, dy) in &DIRECTIONS {
+            match self.threat_if_placed(x, y, dx, dy, Player::Me) {
+                LineThreat::OpenFour | LineThreat::GapFour | LineThreat::BlockedFour => fours += 1,
+                LineThreat::Split | LineThreat::OpenThree => threes += 1,
+                _ => {}
+            }
+        }
+        (fours, threes)
+    }
+
+    // Among equally scored moves, keeps the most forcing ones.
+    fn keep_most_forcing(&self, moves: &mut Vec<(usize, usize)>) {
+        if let Some(most) = moves.iter().map(|&(x, y)| self.threats_made(x, y)).max() {
+            moves.retain(|&(x, y)| self.threats_made(x, y) == most);
+        }
+    }
+
+    // Victory by continuous threats: like VCF, but an open three also counts as an attack, and
+    // every way of stopping it has to lose. A defender's own four ends the line, as in VCF.
+    // Returns the first attacking move.
+    fn vct_move(&self, player: Player, depth: u32) -> Option<(usize, usize)> {
+        self.clone().vct_step(player, depth)
+    }
+
+    fn vct_step(&mut self, player: Player, depth: u32) -> Option<(usize, usize)> {
+        if let Some(cell) = self.winning_move(player) {
+            return Some(cell);
+        }
+        if depth == 0 || self.past_deadline() || self.winning_move(player.other()).is_some() {
+            return None;
+        }
+
+        let threats: Vec<_> = self
+            .candidate_cells()
+            .into_iter()
+            .filter(|&(x, y)| {
+                DIRECTIONS.iter().any(|&(dx, dy)| {
+                    !matches!(
+                        self.threat_if_placed(x, y, dx, dy, player),
+                        LineThreat::Two | LineThreat::BrokenThree | LineThreat::BlockedThree | LineThreat::Other
+                    )
+                })
+            })
+            .collect();
+        for (x, y) in threats {
+            self.place_stone(x, y, player);
+            let defenses = self.threat_defenses(x, y, player);
+            let wins = !defenses.is_empty()
+                && defenses.iter().all(|&(bx, by)| {
+                    self.place_stone(bx, by, player.other());
+                    let wins =
+                        !self.completes_five(bx, by, player.other()) && self.vct_step(player, depth - 1).is_some();
+                    self.undo_stone();
+                    wins
+                });
+            self.undo_stone();
+            if wins {
+                return Some((x, y));
+            }
+        }
+        None
+    }
+
+    fn opponent_has_forced_win(&self, depth: u32) -> bool {
+        self.vct_move(Player::Opponent, depth).is_some()
+    }
+
+    // Our first candidate after which the opponent no longer has a forced win, if any. Past the
+    // deadline a candidate's check can't be trusted, so none is.
+    fn vct_refutation(&mut self) -> Option<(usize, usize)> {
+        self.search_candidates().into_iter().find(|&(x, y)| {
+            if self.is_forbidden(x, y, Player::Me) {
+                return false;
+            }
+            self.place_stone(x, y, Player::Me);
+            let refutes = !self.opponent_has_forced_win(VCT_DEPTH) && !self.past_deadline();
+            self.undo_stone();
+            refutes
+        })
+    }
+
+    // Summed as i64, since weights loaded from a file can be anywhere in i32's range, and then
+    // clamped to ±INF so the result stays safe to add to or negate.
+    fn score_cell(&self, x: usize, y: usize) -> i32 {
+        let mut score: i64 = 0;
+        let mut my_open_threes = 0;
+        let mut my_open_fours = 0;
+        let mut opp_open_threes = 0;
+        let mut opp_open_fours = 0;
+        let mut my_fours_made = 0;
+        let mut opp_fours_made = 0;
+
+        let (mine, opponent) = (&self.weights.mine, &self.weights.opponent);
+        let three_bias = self.opponent_model.three_bias();
+
+        for &(dx, dy) in &DIRECTIONS {
+            let threat = self.evaluate_line_type(x, y, dx, dy, Player::Me);
+            match threat {
+                LineThreat::OpenFour => my_open_fours += 1,
+                LineThreat::Split | LineThreat::OpenThree => my_open_threes += 1,
+                _ => {}
+            }
+            if self.makes_four_along(x, y, dx, dy, Player::Me, threat) {
+                my_fours_made += 1;
+            }
+            score += match threat {
+                LineThreat::Split | LineThreat::OpenThree => i64::from(mine.value(threat)) * (100 + three_bias) / 100,
+                _ => i64::from(mine.value(threat)),
+            };
+            let threat = self.evaluate_line_type(x, y, dx, dy, Player::Opponent);
+            match threat {
+                LineThreat::OpenFour => opp_open_fours += 1,
+                LineThreat::Split | LineThreat::OpenThree => opp_open_threes += 1,
+                _ => {}
+            }
+            if self.makes_four_along(x, y, dx, dy, Player::Opponent, threat) {
+                opp_fours_made += 1;
+            }
+            score += i64::from(opponent.value(threat));
+        }
+
+        if my_open_fours > 0 && my_open_threes > 0 {
+            score += i64::from(mine.four_three);
+        }
+        if opp_open_fours > 0 && opp_open_threes > 0 {
+            score += i64::from(opponent.four_three);
+        }
+        if my_fours_made >= 2 {
+            score += i64::from(mine.double_four);
+        }
+        if opp_fours_made >= 2 {
+            score += i64::from(opponent.double_four);
+        }
+        if my_open_threes >= 2 {
+            score += i64::from(mine.double_three);
+        }
+        if opp_open_threes >= 2 {
+            score += i64::from(opponent.double_three);
+        }
+
+        // Only a tiebreak: capped below the smallest threat weight, so it can't outweigh a threat.
+        let dist = (x.abs_diff(self.center.0) + y.abs_diff(self.center.1)) as i32;
+        let cap = (mine.smallest().min(opponent.smallest()) - 1).max(0);
+        score -= i64::from(dist.saturating_mul(self.center_bias).clamp(-cap, cap));
+        score.clamp(-i64::from(INF), i64::from(INF)) as i32
+    }
+
+    // Whether a stone at the empty cell (x, y) would make a four along (dx, dy), given how the line
+    // reads now. Without a stone here the window has to hold at least two of the side's stones.
+    fn makes_four_along(&self, x: usize, y: usize, dx: isize, dy: isize, player: Player, now: LineThreat) -> bool {
+        !matches!(now, LineThreat::Other)
+            && matches!(
+                self.threat_if_placed(x, y, dx, dy, player),
+                LineThreat::OpenFour | LineThreat::GapFour | LineThreat::BlockedFour
+            )
+    }
+
+    fn heatmap(&self) -> Vec<ScoredMove> {
+        let mut cells = vec![];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.is_empty(x, y) {
+                    cells.push((x, y));
+                }
+            }
+        }
+        cells
+            .into_par_iter()
+            .map(|(x, y)| ScoredMove { x, y, score: self.score_cell(x, y) })
+            .collect()
+    }
+
+    fn candidate_cells(&self) -> Vec<(usize, usize)> {
+        self.cells_within(self.radius)
+    }
+
+    // Empty cells within `radius` steps (Chebyshev) of a stone, or the center on an empty board.
+    fn cells_within(&self, radius: usize) -> Vec<(usize, usize)> {
+        if self.history.is_empty() {
+            return vec![self.center];
+        }
+
+        let mut cells = vec![];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.is_empty(x, y) && self.has_neighbor(x, y, radius) {
+                    cells.push((x, y));
+                }
+            }
+        }
+        cells
+    }
+
+    // The board's reflections and rotations: the four of a rectangle, and the eight of a square.
+    // Index 0 is the identity.
+    fn symmetry_count(&self) -> usize {
+        if self.width == self.height { 8 } else { 4 }
+    }
+
+    fn transform(&self, symmetry: usize, x: usize, y: usize) -> (usize, usize) {
+        let (fx, fy) = (self.width - 1 - x, self.height - 1 - y);
+        match symmetry {
+            0 => (x, y),
+            1 => (fx, y),
+            2 => (x, fy),
+            3 => (fx, fy),
+            4 => (y, x),
+            5 => (fy, fx),
+            6 => (fy, x),
+            _ => (y, fx),
+        }
+    }
+
+    fn inverse_symmetry(symmetry: usize) -> usize {
+        match symmetry {
+            6 => 7,
+            7 => 6,
+            reflection => reflection,
+        }
+    }
+
+    fn is_symmetric_under(&self, symmetry: usize) -> bool {
+        self.history.iter().all(|&(x, y, player)| {
+            let (tx, ty) = self.transform(symmetry, x as usize, y as usize);
+            self.stone_at(tx, ty) == Some(player)
+        })
+    }
+
+    // The book's reply for the game so far, mapped back through whichever symmetry matched it.
+    fn book_move(&self) -> Option<(usize, usize)> {
+        for line in &self.book.0 {
+            if line.moves.len() != self.history.len() || !self.in_bounds(line.reply.0, line.reply.1) {
+                continue;
+            }
+            for symmetry in 0..self.symmetry_count() {
+                let matches = self
+                    .history
+                    .iter()
+                    .zip(&line.moves)
+                    .all(|(&(x, y, _), &cell)| self.transform(symmetry, x as usize, y as usize) == cell);
+                if !matches {
+                    continue;
+                }
+                let (x, y) = self.transform(Self::inverse_symmetry(symmetry), line.reply.0, line.reply.1);
+                if self.is_empty(x, y) {
+                    return Some((x, y));
+                }
+            }
+        }
+        None
+    }
+
+    // Candidate cells with mirror images under the position's own symmetries removed, keeping the
+    // smallest cell of each orbit. Once the stones break every symmetry, all candidates remain.
+    fn canonical_candidates(&self, radius: usize) -> Vec<(usize, usize)> {
+        let symmetries: Vec<usize> = (1..self.symmetry_count()).filter(|&k| self.is_symmetric_under(k)).collect();
+        let mut cells = self.cells_within(radius);
+        cells.retain(|&(x, y)| symmetries.iter().all(|&k| self.transform(k, x, y) >= (x, y)));
+        cells
+    }
+
+    // Candidate cells, best heuristic first, capped at MAX_CANDIDATES. A lone stone has no shape to
+    // read from further off, so the reply to it is looked for next to it.
+    fn search_candidates(&self) -> Vec<(usize, usize)> {
+        let mut radius = if self.expand_below.is_some() || self.history.len() == 1 { 1 } else { self.radius };
+        loop {
+            let cells = self.canonical_candidates(radius);
+            // Scoring only reads the board, and collect keeps scan order so ties sort as before.
+            let mut scored: Vec<_> = cells.into_par_iter().map(|(x, y)| (self.score_cell(x, y), (x, y))).collect();
+            scored.sort_by_key(|&(score, _)| std::cmp::Reverse(score));
+            let best = scored.first().map(|&(score, _)| score);
+            let quiet = self.expand_below.is_some_and(|threshold| best.is_none_or(|best| best < threshold));
+            if quiet && radius < self.radius {
+                radius += 1;
+                continue;
+            }
+            scored.truncate(MAX_CANDIDATES);
+            return scored.into_iter().map(|(_, cell)| cell).collect();
+        }
+    }
+
+    fn has_neighbor(&self, x: usize, y: usize, radius: usize) -> bool {
+        let (x0, x1) = (x.saturating_sub(radius), (x + radius).min(self.width - 1));
+        let (y0, y1) = (y.saturating_sub(radius), (y + radius).min(self.height - 1));
+        let mask = (u64::MAX >> (63 - (x1 - x0))) << x0;
+        (y0..=y1).any(|ny| (self.my_bits[ny] | self.opponent_bits[ny]) & mask != 0)
+    }
+
+    // The side's weight for a line, with our threes weighted by what the opponent model has seen.
+    fn line_value(&self, threat: LineThreat, player: Player) -> i64 {
+        let value = i64::from(self.weights.side(player).value(threat));
+        match threat {
+            LineThreat::OpenThree | LineThreat::Split if player.is_my() => {
+                value * (100 + self.opponent_model.three_bias()) / 100
+            }
+            _ => value,
+        }
+    }
+
+    // The running board score depends on the model, so it's rescored whenever the model changes.
+    fn set_opponent_model(&mut self, model: OpponentModel) {
+        if model != self.opponent_model {
+            self.opponent_model = model;
+            self.board_score = self.full_rescore();
+        }
+    }
+
+    // Static score of the position from my side, as the search sees it at a leaf: threats through
+    // my stones minus the opponent's, once per stone and direction. Kept up to date by
+    // place_stone/undo_stone, see lines_score. The sum is an i64, as weights can be anywhere in
+    // i32's range, and clamped like score_cell.
+    fn static_score(&self) -> i32 {
+        self.board_score.clamp(-i64::from(INF), i64::from(INF)) as i32
+    }
+
+    // The position's score for analysis: each run counted once at its side's weight, my runs for
+    // me and the opponent's against. Scanned from scratch, so too slow for the search.
+    fn evaluate_position(&self) -> i32 {
+        let score: i64 = self
+            .runs()
+            .into_iter()
+            .map(|((player, _, _), threat)| {
+                let value = i64::from(self.weights.side(player).value(threat));
+                if player.is_my() { value } else { -value }
+            })
+            .sum();
+        score.clamp(-i64::from(INF), i64::from(INF)) as i32
+    }
+
+    // A stone's window reaches four cells each way, so a change at (x, y) can only reclassify
+    // stones on the four lines through it, and only in that line's direction.
+    fn lines_score(&self, x: usize, y: usize) -> i64 {
+        let mut score = 0;
+        for &(dx, dy) in &DIRECTIONS {
+            for offset in -4..=4 {
+                let (nx, ny) = (x.wrapping_add_signed(offset * dx), y.wrapping_add_signed(offset * dy));
+                if !self.in_bounds(nx, ny) {
+                    continue;
+                }
+                if self.is_my_move(nx, ny) {
+                    score += self.line_value(self.evaluate_line_type(nx, ny, dx, dy, Player::Me), Player::Me);
+                }
+                if self.is_opponent_move(nx, ny) {
+                    let threat = self.evaluate_line_type(nx, ny, dx, dy, Player::Opponent);
+                    score -= self.line_value(threat, Player::Opponent);
+                }
+            }
+        }
+        score
+    }
+
+    fn full_rescore(&self) -> i64 {
+        let mut score = 0;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let Some(player) = self.stone_at(x, y) else {
+                    continue;
+                };
+                for &(dx, dy) in &DIRECTIONS {
+                    let value = self.line_value(self.evaluate_line_type(x, y, dx, dy, player), player);
+                    score += if player.is_my() { value } else { -value };
+                }
+            }
+        }
+        score
+    }
+
+    // Killers are moves that caused a cutoff at the same depth in a sibling line; trying them
+    // first tends to cut again.
+    fn order_killers(&self, depth: u32, candidates: &mut Vec<(usize, usize)>) {
+        let Some(killers) = self.killers.get(depth as usize) else {
+            return;
+        };
+        for &(x, y) in killers.iter().rev().flatten() {
+            if let Some(pos) = candidates.iter().position(|&cell| cell == (x, y)) {
+                candidates.remove(pos);
+                candidates.insert(0, (x, y));
+            } else if self.is_empty(x, y) {
+                candidates.insert(0, (x, y));
+            }
+        }
+    }
+
+    fn record_killer(&mut self, depth: u32, cell: (usize, usize)) {
+        if self.use_killers
+            && let Some(killers) = self.killers.get_mut(depth as usize)
+            && killers[0] != Some(cell)
+        {
+            killers[1] = killers[0];
+            killers[0] = Some(cell);
+        }
+    }
+
+    // Searches the reply to the stone just placed at (x, y). A four leaves only one answer, so it
+    // doesn't use up depth, up to MAX_EXTENSIONS times along a line.
+    fn search_reply(&mut self, x: usize, y: usize, depth: u32, alpha: i32, beta: i32, player: Player) -> i32 {
+        if self.extensions < MAX_EXTENSIONS && self.makes_four(x, y, player) {
+            self.extensions += 1;
+            let score = self.minimax(depth, alpha, beta, !player.is_my());
+            self.extensions -= 1;
+            score
+        } else {
+            self.minimax(depth - 1, alpha, beta, !player.is_my())
+        }
+    }
+
+    // The side's most recent stone, which is where its fours come from at a leaf: a four made
+    // earlier was either answered or would already have been played out.
+    fn last_stone(&self, player: Player) -> Option<(usize, usize)> {
+        self.history.iter().rev().find(|&&(_, _, p)| p == player).map(|&(x, y, _)| (x as usize, y as usize))
+    }
+
+    // An empty cell on the lines through (x, y) where the side would complete five.
+    fn five_near(&self, x: usize, y: usize, player: Player) -> Option<(usize, usize)> {
+        DIRECTIONS.iter().find_map(|&(dx, dy)| {
+            (-4..=4).map(|offset| (x.wrapping_add_signed(offset * dx), y.wrapping_add_signed(offset * dy))).find(
+                |&(cx, cy)| self.in_bounds(cx, cy) && self.is_empty(cx, cy) && self.completes_five(cx, cy, player),
+            )
+        })
+    }
+
+    // Resolves fours left hanging at the end of the search before trusting the static score: a
+    // four has to be blocked, and the side to move may stand pat or push a four of its own along
+    // the lines through its last stone.
+    fn quiescence(&mut self, mut alpha: i32, mut beta: i32, maximizing: bool, depth: u32) -> i32 {
+        let player = Player::from_is_my(maximizing);
+        let win = if maximizing { WIN_SCORE } else { -WIN_SCORE };
+        if let Some((x, y)) = self.last_stone(player)
+            && self.five_near(x, y, player).is_some()
+        {
+            return win;
+        }
+        let stand_pat = self.static_score();
+        if depth == 0 {
+            return stand_pat;
+        }
+        if let Some((x, y)) = self.last_stone(player.other())
+            && let Some((bx, by)) = self.five_near(x, y, player.other())
+        {
+            if self.is_forbidden(bx, by, player) {
+                return -win;
+            }
+            self.place_stone(bx, by, player);
+            let score = self.quiescence(alpha, beta, !maximizing, depth - 1);
+            self.undo_stone();
+            return score;
+        }
+
+        let mut best = stand_pat;
+        if maximizing {
+            alpha = alpha.max(best);
+        } else {
+            beta = beta.min(best);
+        }
+        if alpha >= beta {
+            return best;
+        }
+        let Some((lx, ly)) = self.last_stone(player) else { return best };
+        let mut fours = vec![];
+        for &(dx, dy) in &DIRECTIONS {
+            // Only a three becomes a four with one more stone.
+            if !matches!(
+                self.evaluate_line_type(lx, ly, dx, dy, player),
+                LineThreat::OpenThree | LineThreat::Split | LineThreat::BrokenThree | LineThreat::BlockedThree
+            ) {
+                continue;
+            }
+            for offset in -4..=4 {
+                let (cx, cy) = (lx.wrapping_add_signed(offset * dx), ly.wrapping_add_signed(offset * dy));
+                if self.in_bounds(cx, cy) && self.is_empty(cx, cy) && !fours.contains(&(cx, cy)) {
+                    fours.push((cx, cy));
+                }
+            }
+        }
+        for (x, y) in fours {
+            if self.is_forbidden(x, y, player) {
+                continue;
+            }
+            self.place_stone(x, y, player);
+            let score = if self.makes_four(x, y, player) {
+                Some(self.quiescence(alpha, beta, !maximizing, depth - 1))
+            } else {
+                None
+            };
+            self.undo_stone();
+            let Some(score) = score else { continue };
+            if maximizing {
+                best = best.max(score);
+                alpha = alpha.max(best);
+            } else {
+                best = best.min(score);
+                beta = beta.min(best);
+            }
+            if alpha >= beta {
+                break;
+            }
+        }
+        best
+    }
+
+    fn past_deadline(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    fn minimax(&mut self, depth: u32, mut alpha: i32, mut beta: i32, maximizing: bool) -> i32 {
+        self.nodes += 1;
+        if depth == 0 {
+            return self.quiescence(alpha, beta, maximizing, QUIESCENCE_DEPTH);
+        }
+        if self.past_deadline() {
+            self.timed_out = true;
+        }
+        if self.timed_out {
+            return 0;
+        }
+        if let Some(&(score, stored_depth)) = self.transpositions.get(&self.canonical_hash())
+            && stored_depth >= depth
+        {
+            return score;
+        }
+        let window = (alpha, beta);
+        let player = Player::from_is_my(maximizing);
+
+        let mut candidates = self.search_candidates();
+        if maximizing {
+            candidates.retain(|&(x, y)| !self.is_forbidden(x, y, Player::Me));
+        }
+        if candidates.is_empty() {
+            return self.static_score();
+        }
+        // Making five wins on the spot, and otherwise an open five cell of the other side has to be
+        // blocked; in either case nothing else is worth searching. Both score high enough in
+        // score_cell to be among the candidates.
+        let five = |side: Player| candidates.iter().copied().find(|&(x, y)| self.completes_five(x, y, side));
+        match five(player).or_else(|| five(player.other())) {
+            Some(cell) => candidates = vec![cell],
+            None => self.order_killers(depth, &mut candidates),
+        }
+
+        let mut best = if maximizing { -INF } else { INF };
+        let mut best_cell = None;
+        for (x, y) in candidates {
+            self.place_stone(x, y, player);
+
+            // A five ends the game here; remaining depth rewards the quicker win.
+            let score = if self.completes_five(x, y, player) {
+                if maximizing { WIN_SCORE + depth as i32 } else { -WIN_SCORE - depth as i32 }
+            } else {
+                self.search_reply(x, y, depth, alpha, beta, player)
+            };
+            self.undo_stone();
+
+            if (maximizing && score > best) || (!maximizing && score < best) {
+                best = score;
+                best_cell = Some((x, y));
+            }
+            if maximizing {
+                alpha = alpha.max(best);
+            } else {
+                beta = beta.min(best);
+            }
+            if alpha >= beta {
+                self.record_killer(depth, (x, y));
+                break;
+            }
+        }
+        if let Some(cell) = best_cell
+            && !self.timed_out
+        {
+            self.best_replies.insert(self.zobrist, cell);
+        }
+
+        // Only exact scores are reusable; bounds from a cut depend on the window.
+        if !self.timed_out && best > window.0 && best < window.1 {
+            self.transpositions.insert(self.canonical_hash(), (best, depth));
+        }
+        best
+    }
+
+   fn find_best_move(&mut self) -> Option<(usize, usize)> {
+        self.find_best_move_with_info().0
+    }
+
+    // Early on every cell scores about the same, so root moves touching our own stones get a
+    // little extra to build a connected shape instead of scattering.
+    fn opening_bonus(&self, x: usize, y: usize) -> i32 {
+        if self.history.len() >= OPENING_PLIES {
+            return 0;
+        }
+        let mut contacts = 0;
+        for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+            let (nx, ny) = (x.wrapping_add_signed(dx), y.wrapping_add_signed(dy));
+            if self.in_bounds(nx, ny) && self.is_my_move(nx, ny) {
+                contacts += 1;
+            }
+        }
+        contacts * OPENING_CONTACT_BONUS
+    }
+
+    // Searches every root move within (alpha, beta). A best score on or outside the window is only
+    // a bound, and the caller has to search again with it widened.
+    fn search_root(&mut self, depth: u32, (alpha, beta): (i32, i32)) -> Option<RootSearch> {
+        let mut scored = vec![];
+        let mut best_moves = vec![];
+        let mut best_score = i32::MIN;
+
+        for (x, y) in self.search_candidates() {
+            if self.is_forbidden(x, y, Player::Me) {
+                continue;
+            }
+            // The bonus is added to the searched score, so the child's window is shifted to match.
+            let bonus = self.opening_bonus(x, y);
+            let lower = best_score.saturating_sub(1).max(alpha);
+            self.place_stone(x, y, Player::Me);
+            let score = if self.completes_five(x, y, Player::Me) {
+                WIN_SCORE + depth as i32
+            } else {
+                bonus + self.search_reply(x, y, depth, lower - bonus, beta - bonus, Player::Me)
+            };
+            self.undo_stone();
+            if self.timed_out {
+                return None;
+            }
+            scored.push((score, (x, y)));
+
+            if score > best_score {
+                best_score = score;
+                best_moves.clear();
+                best_moves.push((x, y));
+            } else if score == best_score {
+                best_moves.push((x, y));
+            }
+            if best_score >= beta {
+                break;
+            }
+        }
+        Some(RootSearch { best_score, best_moves, scored })
+    }
+
+    // Follows the stored best replies from `first`, alternating sides, until the line runs out or
+    // ends in a five. The board is left as it was.
+    fn principal_variation(&mut self, first: (usize, usize)) -> Vec<(usize, usize)> {
+        let mut line = vec![];
+        let mut next = Some(first);
+        let mut player = Player::Me;
+        while let Some((x, y)) = next {
+            if line.len() >= MAX_DEPTH || !self.in_bounds(x, y) || !self.is_empty(x, y) {
+                break;
+            }
+            self.place_stone(x, y, player);
+            line.push((x, y));
+            if self.completes_five(x, y, player) {
+                break;
+            }
+            next = self.best_replies.get(&self.zobrist).copied();
+            player = player.other();
+        }
+        for _ in &line {
+            self.undo_stone();
+        }
+        line
+    }
+
+    // The game as SGF (GM[4] is gomoku), one node per stone in the order played. Columns and rows
+    // count from the top left, a-z then A-Z, so boards over 52 cells a side don't fit.
+    fn to_sgf(&self) -> Option<String> {
+        let letter = |i: usize| match i {
+            0..26 => Some((b'a' + i as u8) as char),
+            26..52 => Some((b'A' + (i - 26) as u8) as char),
+            _ => None,
+        };
+        letter(self.width - 1)?;
+        letter(self.height - 1)?;
+        let size = if self.width == self.height {
+            self.width.to_string()
+        } else {
+            format!("{}:{}", self.width, self.height)
+        };
+        let mut sgf = format!("(;FF[4]GM[4]SZ[{}]", size);
+        for &(x, y, player) in &self.history {
+            let side = if player.is_my() == (self.my_color == Color::Black) { 'B' } else { 'W' };
+            sgf += &format!(";{}[{}{}]", side, letter(x as usize)?, letter(y as usize)?);
+        }
+        sgf.push(')');
+        Some(sgf)
+    }
+
+    // Plays a game from an empty board between two copies of this engine, each seeing the board
+    // from its own side. The result is the winning color, or a draw, also after MAX_SELF_PLAY_PLIES.
+    fn self_play(&self) -> SelfPlayResponse {
+        let mut black = self.clone();
+        black.reset();
+        let mut white = black.clone();
+        white.my_color = Color::White;
+        let mut moves = vec![];
+        let result = loop {
+            let (mover, other, color) = match moves.len() % 2 {
+                0 => (&mut black, &mut white, "black"),
+                _ => (&mut white, &mut black, "white"),
+            };
+            let cell = if mover.history.is_empty() { Some(mover.center) } else { mover.find_best_move() };
+            let Some((x, y)) = cell.filter(|&(x, y)| mover.move_legality(x, y, Player::Me).is_ok()) else {
+                break "draw";
+            };
+            for (game, player) in [(&mut *mover, Player::Me), (&mut *other, Player::Opponent)] {
+                game.place_stone(x, y, player);
+                game.capture(x, y, player);
+                game.first_move = false;
+            }
+            moves.push(CoordOut::from_usize(x, y));
+            if mover.completes_five(x, y, Player::Me) {
+                break color;
+            }
+            if mover.is_draw() || moves.len() >= MAX_SELF_PLAY_PLIES {
+                break "draw";
+            }
+        };
+        SelfPlayResponse { moves, result }
+    }
+
+    fn pick(&mut self, cells: &[(usize, usize)]) -> Option<(usize, usize)> {
+        let in_line: Vec<(usize, usize)>;
+        let cells = match self.last_stone(Player::Me).filter(|_| self.follow_plan) {
+            Some((lx, ly)) => {
+                in_line = cells
+                    .iter()
+                    .copied()
+                    .filter(|&(x, y)| {
+                        let (dx, dy) = (x.abs_diff(lx), y.abs_diff(ly));
+                        (dx == 0 || dy == 0 || dx == dy) && dx.max(dy) <= 4
+                    })
+                    .collect();
+                if in_line.is_empty() { cells } else { &in_line }
+            }
+            None => cells,
+        };
+        if self.deterministic {
+            cells.iter().copied().min_by_key(|&(x, y)| (y, x))
+        } else {
+            cells.choose(&mut self.rng).copied()
+        }
+    }
+
+    fn forced_move(&self, cell: (usize, usize), reason: &'static str) -> (Option<(usize, usize)>, DecisionInfo) {
+        let info = DecisionInfo {
+            r#move: Some(CoordOut::from_usize(cell.0, cell.1)),
+            score: self.score_cell(cell.0, cell.1),
+            reason,
+            pv: vec![CoordOut::from_usize(cell.0, cell.1)],
+            ..Default::default()
+        };
+        (Some(cell), info)
+    }
+
+    // The reflection of (x, y) through the center, if we may play there.
+    fn mirror_move(&self, x: usize, y: usize) -> Option<(usize, usize)> {
+        let (mx, my) = (self.width - 1 - x, self.height - 1 - y);
+        (self.is_empty(mx, my) && !self.is_forbidden(mx, my, Player::Me)).then_some((mx, my))
+    }
+
+    // Wraps the decision with the counters the stats command reports.
+    fn find_best_move_with_info(&mut self) -> (Option<(usize, usize)>, DecisionInfo) {
+        let started = Instant::now();
+        self.nodes = 0;
+        let decision = self.decide_move();
+        self.deadline = None;
+        self.timed_out = false;
+        self.last_nodes = self.nodes;
+        self.last_think = started.elapsed();
+        self.total_think += self.last_think;
+        self.think_times.record(self.last_think);
+        decision
+    }
+
+    fn decide_move(&mut self) -> (Option<(usize, usize)>, DecisionInfo) {
+        debug_assert_eq!(self.board_score, self.full_rescore());
+
+        if let Some(cell) = self.winning_move(Player::Me) {
+            return self.forced_move(cell, "five");
+        }
+        if let Some(cell) = self.winning_move(Player::Opponent)
+            && !self.is_forbidden(cell.0, cell.1, Player::Me)
+        {
+            return self.forced_move(cell, "block");
+        }
+        if let Some(cell) = self.book_move()
+            && !self.is_forbidden(cell.0, cell.1, Player::Me)
+        {
+            return self.forced_move(cell, "book");
+        }
+        // The forced-win searches below count against the time budget too, and give up at the
+        // deadline as if they had found nothing.
+        let started = Instant::now();
+        self.deadline = Some(started + self.time_budget);
+        if let Some(sequence) = self.vcf_search(Player::Me, VCF_DEPTH)
+            && !self.is_forbidden(sequence[0].0, sequence[0].1, Player::Me)
+        {
+            let (cell, mut info) = self.forced_move(sequence[0], "vcf");
+            info.vcf = sequence.iter().map(|&(x, y)| CoordOut::from_usize(x, y)).collect();
+            info.pv = info.vcf.clone();
+            return (cell, info);
+        }
+
+        for (x, y) in self.candidate_cells() {
+            if self.is_forbidden(x, y, Player::Me) {
+                continue;
+            }
+            let mut my_fours = 0;
+            let mut my_threes = 0;
+            for &(dx, dy) in &DIRECTIONS {
+                match self.evaluate_line_type(x, y, dx, dy, Player::Me) {
+                    LineThreat::OpenFour => my_fours += 1,
+                    LineThreat::OpenThree => my_threes += 1,
+                    _ => {}
+                }
+            }
+            if my_fours > 0 || my_threes >= 2 {
+                return self.forced_move((x, y), "threat");
+            }
+        }
+        // Nothing of ours outpaces an opponent fork, so take the fork point it values most.
+        if let Some(&cell) = self
+            .opponent_fork_cells()
+            .iter()
+            .filter(|&&(x, y)| !self.is_forbidden(x, y, Player::Me))
+            .max_by_key(|&&(x, y)| self.score_cell(x, y))
+        {
+            return self.forced_move(cell, "fork");
+        }
+        if self.opponent_has_forced_win(VCT_DEPTH)
+            && let Some(cell) = self.vct_refutation()
+        {
+            return self.forced_move(cell, "vct");
+        }
+
+        // Iterative deepening: only fully completed depths count, and depth 1 ignores the clock so
+        // there is always a move to fall back on.
+        let mut completed = None;
+        let mut scores = vec![];
+        self.killers = Default::default();
+        self.nodes = 0;
+        for depth in 1..=self.search_depth.max(1) {
+            self.deadline = (depth > 1).then(|| started + self.time_budget);
+            // Aspiration: expect a score near the last one from a search of the same parity, since
+            // the score swings with whoever moved last. A win needs no window.
+            let window = match scores.len().checked_sub(2).map(|i| scores[i]) {
+                Some(last) if i32::abs(last) < WIN_SCORE => (last - ASPIRATION_WINDOW, last + ASPIRATION_WINDOW),
+                _ => (-INF, INF),
+            };
+            let mut result = self.search_root(depth, window);
+            if let Some(root) = &result
+                && window != (-INF, INF)
+                && (root.best_score <= window.0 || root.best_score >= window.1)
+            {
+                result = self.search_root(depth, (-INF, INF));
+            }
+            // With no root move we may play, deeper searches find none either.
+            let stuck = result.as_ref().is_some_and(|root| root.best_moves.is_empty());
+            match result {
+                Some(result) => {
+                    scores.push(result.best_score);
+                    completed = Some((depth, result));
+                }
+                None => break,
+            }
+            if stuck || started.elapsed() >= self.time_budget {
+                break;
+            }
+        }
+
+        let Some((depth, RootSearch { best_score, mut best_moves, mut scored })) = completed else {
+            return (None, DecisionInfo::default());
+        };
+        self.keep_most_forcing(&mut best_moves);
+        let best = self.pick(&best_moves);
+        let pv = best.map(|cell| self.principal_variation(cell)).unwrap_or_default();
+        scored.sort_by_key(|&(score, _)| std::cmp::Reverse(score));
+        let info = DecisionInfo {
+            r#move: best.map(|(x, y)| CoordOut::from_usize(x, y)),
+            score: best_score,
+            reason: "search",
+            depth,
+            nodes: self.nodes,
+            runners_up: scored
+                .into_iter()
+                .filter(|&(_, cell)| Some(cell) != best)
+                .take(3)
+                .map(|(score, (x, y))| ScoredMove { x, y, score })
+                .collect(),
+            pv: pv.into_iter().map(|(x, y)| CoordOut::from_usize(x, y)).collect(),
+            ..Default::default()
+        };
+        (best, info)
+    }
+}
+
+
+
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum ErrorCode {
+    InvalidJson,
+    UnsupportedVersion,
+    UnknownCommand,
+    MissingField,
+    InvalidCoordinate,
+    OutOfBounds,
+    Occupied,
+    Forbidden,
+    IllegalMove,
+    NotFirstMove,
+    Swap2Pending,
+    InvalidSwap2,
+    NoMoveFound,
+    NoMatch,
+    InvalidPath,
+    SaveFailed,
+    LoadFailed,
+    RateLimited,
+    MessageTooLarge,
+    ServerBusy,
+    BoardTooLarge,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail<'a> {
+    code: ErrorCode,
+    message: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ErrorReply<'a> {
+    Coded { error: ErrorDetail<'a> },
+    Flat { error: &'a str },
+}
+
+fn error(code: ErrorCode, msg: &str) -> ErrorReply<'_> {
+    if FLAT_ERRORS.load(Ordering::Relaxed) {
+        ErrorReply::Flat { error: msg }
+    } else {
+        ErrorReply::Coded { error: ErrorDetail { code, message: msg } }
+    }
+}
+
+fn respond<T: Serialize>(body: &T) -> String {
+    serde_json::to_string(&Versioned { version: PROTOCOL_VERSION, body }).unwrap()
+}
+
+fn parse_gtp_color(arg: Option<&str>) -> Option<Color> {
+    match arg?.to_ascii_lowercase().as_str() {
+        "b" | "black" => Some(Color::Black),
+        "w" | "white" => Some(Color::White),
+        _ => None,
+    }
+}
+
+// GTP-style text protocol: `= result` on success and `? message` on failure.
+fn handle_gtp(game: &mut GameState, line: &str) -> String {
+    let mut args = line.split_whitespace();
+    match args.next() {
+        Some("play") => {
+            let Some(color) = parse_gtp_color(args.next()) else {
+                return "? invalid color\n".into();
+            };
+            let coords = (args.next().and_then(|x| x.parse().ok()), args.next().and_then(|y| y.parse().ok()));
+            let (Some(x), Some(y)) = coords else {
+                return "? invalid coordinate\n".into();
+            };
+            let player = Player::from_is_my(color == game.my_color);
+            if game.move_legality(x, y, player).is_err() {
+                return "? illegal move\n".into();
+            }
+            game.place_stone(x, y, player);
+            game.capture(x, y, player);
+            game.first_move = false;
+            "=\n".into()
+        }
+        Some("genmove") => {
+            let Some(color) = parse_gtp_color(args.next()) else {
+                return "? invalid color\n".into();
+            };
+            game.set_my_color(color);
+            match game.find_best_move() {
+                Some((x, y)) => {
+                    game.place_stone(x, y, Player::Me);
+                    game.capture(x, y, Player::Me);
+                    game.first_move = false;
+                    format!("= {} {}\n", x, y)
+                }
+                None => "= pass\n".into(),
+            }
+        }
+        Some("quit") => "=\n".into(),
+        Some("clear_board") => {
+            game.reset();
+            "=\n".into()
+        }
+        _ => "? unknown command\n".into(),
+    }
+}
+
+// Applies one JSON command to a game and returns the reply; all IO stays with the caller.
+fn process_command(game: &mut GameState, cmd: Command, team: &str) -> String {
+    if cmd.version != PROTOCOL_VERSION {
+        return respond(&error(ErrorCode::UnsupportedVersion, "Unsupported protocol version"));
+    }
+    // Commands on one game run one at a time under its lock, so of two racing starts the first
+    // opens the game and the second finds it under way. With `force` the second one clears the
+    // board, keeping our color and what we've learned about the opponent, and starts over instead.
+    if cmd.command == "start" && cmd.force && !game.first_move {
+        let color = game.my_color;
+        game.next_game();
+        game.my_color = color;
+    }
+    // The next game of a match starts with a start, a swap2 opening, or an opponent move that isn't
+    // the last one resent.
+    if game.game_over {
+        let next = match cmd.command.as_str() {
+            "start" | "swap2_propose" => true,
+            "move" => cmd
+                .opponent_move
+                .as_ref()
+                .and_then(|c| c.to_xy().ok())
+                .is_some_and(|cell| game.last_stone(Player::Opponent) != Some(cell)),
+            _ => false,
+        };
+        if next {
+            game.next_game();
+        }
+    }
+    let reply = match cmd.command.as_str() {
+        // Playing white, set explicitly or by set_color, means waiting for the opponent's stone.
+        "start" if cmd.first == Some(false) || (cmd.first.is_none() && game.my_color == Color::White) => {
+            if game.first_move {
+                game.first_move = false;
+                game.my_color = Color::White;
+                respond(&Reply { reply: "ready".into() })
+            } else {
+                respond(&error(ErrorCode::NotFirstMove, "Not first move"))
+            }
+        }
+        "start" => {
+            if game.first_move {
+                let (cx, cy) = game.center;
+                game.place_stone(cx, cy, Player::Me);
+                game.first_move = false;
+                game.my_color = Color::Black;
+                respond(&MoveResponse {
+                    r#move: CoordOut::from_usize(cx, cy),
+                    team: team.to_string(),
+                    winner: None,
+                    result: None,
+                    depth: None,
+                })
+            } else {
+                respond(&error(ErrorCode::NotFirstMove, "Not first move"))
+            }
+        }
+        "swap2_propose" => {
+            if game.first_move {
+                let stones = game
+                    .propose_swap2()
+                    .iter()
+                    .map(|&(x, y, color)| StoneOut { x, y, color })
+                    .collect();
+                respond(&Swap2Response { stones })
+            } else {
+                respond(&error(ErrorCode::NotFirstMove, "Not first move"))
+            }
+        }
+        "swap2_decide" => {
+            let extra = cmd
+                .stones
+                .iter()
+                .flatten()
+                .map(CoordIn::to_xy)
+                .collect::<Result<Vec<_>, _>>();
+            match extra {
+                Err(_) => respond(&error(ErrorCode::InvalidCoordinate, "Invalid coordinate")),
+                Ok(extra) => match game.decide_swap2(cmd.choice.as_deref().unwrap_or_default(), &extra) {
+                    Err(msg) => respond(&error(ErrorCode::InvalidSwap2, msg)),
+                    Ok(color) => {
+                        let reply = if color == Color::White { game.find_best_move() } else { None };
+                        if let Some((x, y)) = reply {
+                            game.place_stone(x, y, Player::Me);
+                        }
+                        respond(&ColorResponse {
+                            color,
+                            r#move: reply.map(|(x, y)| CoordOut::from_usize(x, y)),
+                        })
+                    }
+                },
+            }
+        }
+        "move" if !game.opening.is_empty() => {
+            respond(&error(ErrorCode::Swap2Pending, "Swap2 decision pending"))
+        }
+        "move" => {
+            if let Some(c) = cmd.opponent_move {
+                match c.to_xy() {
+                    Err(_) => respond(&error(ErrorCode::InvalidCoordinate, "Invalid coordinate")),
+                    Ok((x, y)) if !game.in_bounds(x, y) => {
+                        respond(&error(ErrorCode::OutOfBounds, "Coordinate out of bounds"))
+                    }
+                    Ok((x, y)) if !game.is_empty(x, y) => {
+                        // A client that lost our reply may resend its last move, so
+                        // repeat the answer rather than play twice. Any other occupied
+                        // cell would corrupt the board.
+                        let last = game.history.len().saturating_sub(2);
+                        match game.history[last..] {
+                            [(ox, oy, Player::Opponent), (bx, by, Player::Me)]
+                                if (ox as usize, oy as usize) == (x, y) =>
+                            {
+                                let (bx, by) = (bx as usize, by as usize);
+                                let winner = game.completes_five(bx, by, Player::Me);
+                                respond(&MoveResponse {
+                                    r#move: CoordOut::from_usize(bx, by),
+                                    team: team.to_string(),
+                                    winner: winner.then(|| team.to_string()),
+                                    result: (!winner && game.is_draw()).then_some("draw"),
+                                    depth: None,
+                                })
+                            }
+                            [.., (ox, oy, Player::Opponent)]
+                                if (ox as usize, oy as usize) == (x, y)
+                                    && game.completes_five(x, y, Player::Opponent) =>
+                            {
+                                respond(&GameOver { winner: "opponent" })
+                            }
+                            _ => respond(&error(ErrorCode::Occupied, "Opponent move on occupied cell")),
+                        }
+                    }
+                    Ok(_) if game.is_draw() => {
+                        game.finish_game(Outcome::Draw);
+                        respond(&DrawResponse { result: "draw" })
+                    }
+                    Ok((x, y)) => {
+                        let threes = game.open_threes(Player::Me);
+                        let model = game.opponent_model;
+                        game.place_stone(x, y, Player::Opponent);
+                        let captured = game.capture(x, y, Player::Opponent);
+                        let mut seen = model;
+                        seen.observe(threes, game.open_threes(Player::Me));
+                        game.set_opponent_model(seen);
+                        if game.completes_five(x, y, Player::Opponent) {
+                            game.finish_game(Outcome::Loss);
+                            respond(&GameOver { winner: "opponent" })
+                        } else if game.is_draw() {
+                            game.finish_game(Outcome::Draw);
+                            respond(&DrawResponse { result: "draw" })
+                        } else {
+                            let mirror = game.strategy == Strategy::Mirror;
+                            let (best, info) = match game.mirror_move(x, y).filter(|_| mirror) {
+                                Some(cell) => game.forced_move(cell, "mirror"),
+                                None => game.find_best_move_with_info(),
+                            };
+                            if game.verbose {
+                                log!(Debug, "{}", serde_json::to_string(&info).unwrap());
+                            }
+                            let reply = match best {
+                                Some((bx, by)) if game.in_bounds(bx, by) && game.is_empty(bx, by) => Ok((bx, by)),
+                                Some(_) => Err((ErrorCode::Occupied, "Move already taken")),
+                                None => Err((ErrorCode::NoMoveFound, "No valid move found")),
+                            };
+                            match reply {
+                                Ok((bx, by)) => {
+                                    game.place_stone(bx, by, Player::Me);
+                                    game.capture(bx, by, Player::Me);
+                                    let winner = game.completes_five(bx, by, Player::Me);
+                                    let draw = !winner && game.is_draw();
+                                    if winner {
+                                        game.finish_game(Outcome::Win);
+                                    } else if draw {
+                                        game.finish_game(Outcome::Draw);
+                                    }
+                                    respond(&MoveResponse {
+                                        r#move: CoordOut::from_usize(bx, by),
+                                        team: team.to_string(),
+                                        winner: winner.then(|| team.to_string()),
+                                        result: draw.then_some("draw"),
+                                        depth: game.report_depth.then_some(info.depth),
+                                    })
+                                }
+                                Err((code, msg)) => {
+                                    // Without a reply the opponent move isn't kept either, so
+                                    // the client can resend it. Captures stay in the history order,
+                                    // so the opponent's stone is still the last entry.
+                                    game.undo_stone();
+                                    for (cx, cy) in captured {
+                                        game.place_stone(cx, cy, Player::Me);
+                                    }
+                                    game.set_opponent_model(model);
+                                    respond(&error(code, msg))
+                                }
+                            }
+                        }
+                    }
+                }
+            } else {
+                respond(&error(ErrorCode::MissingField, "No opponent move"))
+            }
+        }
+        // Sides alternate from the opponent unless `first` says we opened; a `side` tag overrides.
+        "load_game" => {
+            let opener = if cmd.first == Some(true) { Player::Me } else { Player::Opponent };
+            let moves = cmd.moves.unwrap_or_default();
+            let parsed: Result<Vec<_>, usize> = moves
+                .iter()
+                .enumerate()
+                .map(|(i, m)| {
+                    let alternate = if i % 2 == 0 { opener } else { opener.other() };
+                    match (m.x.as_usize(), m.y.as_usize()) {
+                        (Ok(x), Ok(y)) => Ok((x, y, m.side.unwrap_or(alternate))),
+                        _ => Err(i),
+                    }
+                })
+                .collect();
+            let mut replay = game.clone();
+            match parsed.and_then(|moves| replay.load_game(&moves)) {
+                Ok(()) => {
+                    *game = replay;
+                    respond(&Reply { reply: "ok".into() })
+                }
+                Err(i) => respond(&error(ErrorCode::IllegalMove, &format!("Illegal move at index {}", i))),
+            }
+        }
+        "board" => respond(&BoardResponse {
+            width: game.width,
+            height: game.height,
+            moves: game.history.len(),
+            my: game
+                .stones(Player::Me)
+                .into_iter()
+                .map(|(x, y)| CoordOut::from_usize(x, y))
+                .collect(),
+            opponent: game
+                .stones(Player::Opponent)
+                .into_iter()
+                .map(|(x, y)| CoordOut::from_usize(x, y))
+                .collect(),
+        }),
+        "save" => match cmd.path.as_deref().map(|name| game.state_path(name)) {
+            None => respond(&error(ErrorCode::MissingField, "No path")),
+            Some(None) => respond(&error(ErrorCode::InvalidPath, "Invalid path")),
+            Some(Some(path)) => match game.save(&path) {
+                Ok(()) => respond(&Reply { reply: "ok".into() }),
+                Err(_) => respond(&error(ErrorCode::SaveFailed, "Could not save state")),
+            },
+        },
+        "load" => match cmd.path.as_deref().map(|name| game.state_path(name)) {
+            None => respond(&error(ErrorCode::MissingField, "No path")),
+            Some(None) => respond(&error(ErrorCode::InvalidPath, "Invalid path")),
+            Some(Some(path)) => match GameState::load(&path) {
+                Ok(loaded) => {
+                    let (verbose, print_board, book) = (game.verbose, game.print_board, Arc::clone(&game.book));
+                    let (report_depth, state_dir) = (game.report_depth, game.state_dir.take());
+                    *game = loaded;
+                    game.verbose = verbose;
+                    game.print_board = print_board;
+                    game.report_depth = report_depth;
+                    game.book = book;
+                    game.state_dir = state_dir;
+                    respond(&Reply { reply: "ok".into() })
+                }
+                Err(_) => respond(&error(ErrorCode::LoadFailed, "Could not load state")),
+            },
+        },
+        "stats" => respond(&StatsResponse {
+            moves: game.history.len(),
+            last_nodes: game.last_nodes,
+            last_time_ms: game.last_think.as_millis() as u64,
+            total_time_ms: game.total_think.as_millis() as u64,
+            fill: game.history.len() as f64 / (game.width * game.height) as f64,
+            opponent_model: game.opponent_model,
+        }),
+        "timing" => respond(&game.think_times),
+        "export" => match game.to_sgf() {
+            Some(sgf) => respond(&ExportResponse { sgf }),
+            None => respond(&error(ErrorCode::BoardTooLarge, "Board too large to export")),
+        },
+        "eval" => respond(&EvalResponse { score: game.evaluate_position() }),
+        "selfplay" => respond(&game.self_play()),
+        "heatmap" => respond(&HeatmapResponse { cells: game.heatmap() }),
+        // The search scribbles on the board, killers and table, so it runs on a copy.
+        "hint" => match game.clone().find_best_move() {
+            Some((x, y)) => respond(&HintResponse { hint: CoordOut::from_usize(x, y) }),
+            None => respond(&error(ErrorCode::NoMoveFound, "No valid move found")),
+        },
+        // Our answer to a hypothetical opponent move, worked out on a copy of the game.
+        "refute" => match cmd.opponent_move.as_ref().map(CoordIn::to_xy) {
+            None => respond(&error(ErrorCode::MissingField, "No opponent move")),
+            Some(Err(_)) => respond(&error(ErrorCode::InvalidCoordinate, "Invalid coordinate")),
+            Some(Ok((x, y))) => match game.move_legality(x, y, Player::Opponent) {
+                Err((code, reason)) => respond(&error(code, reason)),
+                Ok(()) => {
+                    let mut copy = game.clone();
+                    copy.place_stone(x, y, Player::Opponent);
+                    copy.capture(x, y, Player::Opponent);
+                    if copy.completes_five(x, y, Player::Opponent) {
+                        respond(&GameOver { winner: "opponent" })
+                    } else {
+                        match copy.find_best_move() {
+                            Some((bx, by)) => respond(&RefuteResponse { refutation: CoordOut::from_usize(bx, by) }),
+                            None => respond(&error(ErrorCode::NoMoveFound, "No valid move found")),
+                        }
+                    }
+                }
+            },
+        },
+        "analyze" => {
+            let (best, info) = game.clone().find_best_move_with_info();
+            respond(&AnalyzeResponse {
+                best_move: best.map(|(x, y)| CoordOut::from_usize(x, y)),
+                score: info.score,
+                pv: info.pv,
+            })
+        }
+        "undo" => {
+            let undone = game.undo(cmd.n.unwrap_or(1));
+            respond(&UndoResponse { undone, moves: game.history.len() })
+        }
+        "set_color" => match cmd.color {
+            None => respond(&error(ErrorCode::MissingField, "No color")),
+            Some(color) => {
+                game.set_my_color(color);
+                respond(&ColorResponse { color, r#move: None })
+            }
+        },
+        "force" if !game.opening.is_empty() => respond(&error(ErrorCode::Swap2Pending, "Swap2 decision pending")),
+        // Plays the given cell as our move instead of searching for one.
+        "force" => match cmd.r#move.as_ref().map(CoordIn::to_xy) {
+            None => respond(&error(ErrorCode::MissingField, "No move")),
+            Some(Err(_)) => respond(&error(ErrorCode::InvalidCoordinate, "Invalid coordinate")),
+            Some(Ok((x, y))) => match game.move_legality(x, y, Player::Me) {
+                Err((code, reason)) => respond(&error(code, reason)),
+                Ok(()) => {
+                    game.place_stone(x, y, Player::Me);
+                    game.capture(x, y, Player::Me);
+                    game.first_move = false;
+                    let winner = game.completes_five(x, y, Player::Me);
+                    let draw = !winner && game.is_draw();
+                    if winner {
+                        game.finish_game(Outcome::Win);
+                    } else if draw {
+                        game.finish_game(Outcome::Draw);
+                    }
+                    respond(&MoveResponse {
+                        r#move: CoordOut::from_usize(x, y),
+                        team: team.to_string(),
+                        winner: winner.then(|| team.to_string()),
+                        result: draw.then_some("draw"),
+                        depth: None,
+                    })
+                }
+            },
+        },
+        // Asks whether the client's stone could go on a cell, without playing it.
+        "is_legal" => match cmd.r#move.as_ref().map(CoordIn::to_xy) {
+            None => respond(&error(ErrorCode::MissingField, "No move")),
+            Some(Err(_)) => respond(&error(ErrorCode::InvalidCoordinate, "Invalid coordinate")),
+            Some(Ok((x, y))) => {
+                let legality = game.move_legality(x, y, Player::Opponent);
+                respond(&LegalityResponse { legal: legality.is_ok(), reason: legality.err().map(|(_, reason)| reason) })
+            }
+        },
+        "new_match" => match cmd.n.filter(|&games| games > 0) {
+            None => respond(&error(ErrorCode::MissingField, "No game count")),
+            Some(games) => {
+                game.reset();
+                game.match_tally = Some(MatchTally { games, ..Default::default() });
+                respond(&Reply { reply: "ok".into() })
+            }
+        },
+        "match_status" => match &game.match_tally {
+            None => respond(&error(ErrorCode::NoMatch, "No match")),
+            Some(tally) => respond(tally),
+        },
+        // How the evaluator reads each line through a cell, for both sides.
+        "threats" => match cmd.r#move.as_ref().map(CoordIn::to_xy) {
+            None => respond(&error(ErrorCode::MissingField, "No move")),
+            Some(Err(_)) => respond(&error(ErrorCode::InvalidCoordinate, "Invalid coordinate")),
+            Some(Ok((x, y))) if !game.in_bounds(x, y) => {
+                respond(&error(ErrorCode::OutOfBounds, "Coordinate out of bounds"))
+            }
+            Some(Ok((x, y))) => respond(&ThreatsResponse {
+                directions: DIRECTIONS
+                    .iter()
+                    .map(|&(dx, dy)| DirectionThreats {
+                        dx,
+                        dy,
+                        mine: game.evaluate_line_type(x, y, dx, dy, Player::Me),
+                        opponent: game.evaluate_line_type(x, y, dx, dy, Player::Opponent),
+                    })
+                    .collect(),
+            }),
+        },
+        "threat_list" => respond(&ThreatListResponse { threats: game.list_threats() }),
+        // The history grows and shrinks with every stone, so this needs no scan of the board.
+        "remaining" => respond(&RemainingResponse {
+            empty: game.width * game.height - game.history.len(),
+            total: game.width * game.height,
+        }),
+        "quit" => respond(&Reply { reply: "bye".into() }),
+        "reset" => {
+            game.reset();
+            respond(&Reply { reply: "ok".into() })
+        }
+        _ => respond(&error(ErrorCode::UnknownCommand, "Unknown command")),
+    };
+    if game.print_board && cmd.command == "move" {
+        eprint!("{}", game.render());
+    }
+    reply
+}
+
+// Turns one request line into its reply, whichever transport carried it, and whether the client
+// asked to quit after it. Commands from a network peer count against the rate limit.
+fn process_line(server: &Server, peer: Option<IpAddr>, line: &str) -> (String, bool) {
+    if peer.is_some_and(|ip| server.rate_limited(ip)) {
+        (server.error_reply(ErrorCode::RateLimited, "Rate limited"), false)
+    } else if server.gtp {
+        let state = server.session("");
+        let mut game = lock(&state);
+        (handle_gtp(&mut game, line), line.split_whitespace().next() == Some("quit"))
+    } else {
+        match serde_json::from_str::<Command>(line) {
+            // Liveness check: answered without looking up, or waiting on, any session.
+            Ok(cmd) if cmd.command == "ping" && cmd.version == PROTOCOL_VERSION => {
+                (respond(&Reply { reply: "pong".into() }), false)
+            }
+            // A whole game of searches: it plays on a copy, so the game's lock isn't held meanwhile.
+            Ok(cmd) if cmd.command == "selfplay" && cmd.version == PROTOCOL_VERSION => {
+                let state = server.session(cmd.session.as_deref().unwrap_or_default());
+                let game = lock(&state).clone();
+                (respond(&game.self_play()), false)
+            }
+            Ok(cmd) => {
+                let quit = cmd.command == "quit" && cmd.version == PROTOCOL_VERSION;
+                let state = server.session(cmd.session.as_deref().unwrap_or_default());
+                let mut game = lock(&state);
+                (process_command(&mut game, cmd, &server.team), quit)
+            }
+            Err(_) => (respond(&error(ErrorCode::InvalidJson, "Wrong JSON format")), false),
+        }
+    }
+}
+
+// `--framing <newline|length>`: how requests and replies are delimited on a TCP connection, either
+// one per line or each behind a 4-byte big-endian length.
+#[derive(Clone, Copy)]
+enum Framing {
+    Newline,
+    Length,
+}
+
+enum Frame {
+    Request(String),
+    TooLarge,
+    Closed,
+}
+
+impl Framing {
+    // Reads one request however the bytes were split into segments, leaving anything after it
+    // buffered for the next. A request over the limit is skipped.
+    fn read(self, reader: &mut impl BufRead) -> io::Result<Frame> {
+        match self {
+            Framing::Newline => {
+                // Bytes, not a String: the limit can cut a character in half, and read_line would
+                // take that for bad UTF-8 rather than an oversized line.
+                let mut buf = vec![];
+                match reader.by_ref().take(MAX_MESSAGE_BYTES as u64 + 1).read_until(b'\n', &mut buf)? {
+                    0 => Ok(Frame::Closed),
+                    n if n > MAX_MESSAGE_BYTES && buf.last() != Some(&b'\n') => {
+                        reader.skip_until(b'\n')?;
+                        Ok(Frame::TooLarge)
+                    }
+                    _ => Ok(Frame::Request(String::from_utf8_lossy(&buf).into_owned())),
+                }
+            }
+            Framing::Length => {
+                let mut header = [0; 4];
+                match reader.read_exact(&mut header) {
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(Frame::Closed),
+                    result => result?,
+                }
+                let len = u32::from_be_bytes(header) as usize;
+                if len > MAX_MESSAGE_BYTES {
+                    io::copy(&mut reader.by_ref().take(len as u64), &mut io::sink())?;
+                    return Ok(Frame::TooLarge);
+                }
+                let mut buf = vec![0; len];
+                reader.read_exact(&mut buf)?;
+                Ok(Frame::Request(String::from_utf8_lossy(&buf).into_owned()))
+            }
+        }
+    }
+
+    fn write(self, out: &mut impl Write, reply: &str) -> io::Result<()> {
+        match self {
+            Framing::Newline => out.write_all(format!("{}\n", reply).as_bytes()),
+            Framing::Length => {
+                out.write_all(&(reply.len() as u32).to_be_bytes())?;
+                out.write_all(reply.as_bytes())
+            }
+        }
+    }
+}
+
+fn handle_client(mut sock: TcpStream, server: Arc<Server>) {
+    let peer = sock.peer_addr().unwrap();
+    let mut reader = BufReader::new(sock.try_clone().unwrap());
+
+    loop {
+        let buf = match server.framing.read(&mut reader) {
+            Ok(Frame::Request(buf)) => buf,
+            Ok(Frame::TooLarge) => {
+                let reply = server.error_reply(ErrorCode::MessageTooLarge, "Message too large");
+                if server.framing.write(&mut sock, &reply).is_err() {
+                    break;
+                }
+                continue;
+            }
+            Ok(Frame::Closed) => {
+                log!(Info, "Connection to {} closed by peer", peer);
+                break;
+            }
+            // Read timeouts surface as WouldBlock on Unix and TimedOut on Windows.
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                log!(Info, "Closing idle connection to {}", peer);
+                break;
+            }
+            Err(e) => {
+                log!(Error, "Read error from {}: {}", peer, e);
+                break;
+            }
+        };
+        let line = buf.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (reply, quit) = process_line(&server, Some(peer.ip()), line);
+        if server.framing.write(&mut sock, &reply).is_err() {
+            log!(Error, "Lost connection to {}", peer);
+            break;
+        }
+        if quit {
+            log!(Info, "Client {} quit", peer);
+            let _ = sock.shutdown(Shutdown::Both);
+            break;
+        }
+    }
+}
+
+type Connection = (TcpStream, bool, Arc<AtomicBool>);
+
+fn handle_connection(sock: TcpStream, websocket: bool, server: Arc<Server>) {
+    if websocket { handle_websocket(sock, server) } else { handle_client(sock, server) }
+}
+
+// Same protocol as handle_client, one command per text message.
+fn handle_websocket(sock: TcpStream, server: Arc<Server>) {
+    let peer = sock.peer_addr().unwrap();
+    let mut ws = match tungstenite::accept(sock) {
+        Ok(ws) => ws,
+        Err(e) => {
+            log!(Error, "WebSocket handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+
+    loop {
+        let text = match ws.read() {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) | Err(_) => break,
+            Ok(_) => continue,
+        };
+        let line = text.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (reply, quit) = process_line(&server, Some(peer.ip()), line);
+        if ws.send(Message::Text(reply)).is_err() {
+            log!(Error, "Lost connection to {}", peer);
+            break;
+        }
+        if quit {
+            log!(Info, "Client {} quit", peer);
+            let _ = ws.close(None);
+            let _ = ws.flush();
+            break;
+        }
+    }
+}
+
+// Offline mode: one command per input line, each reply on its own output line, in order.
+fn run_batch(server: &Server, input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (reply, quit) = process_line(server, None, line);
+        writeln!(out, "{}", reply)?;
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn request_shutdown(_: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+// --bind takes a full socket address, or just an IP (v4 or v6) to combine with -p.
+fn bind_address(bind: Option<&str>, port: u16) -> Option<SocketAddr> {
+    match bind {
+        None => Some(SocketAddr::from(([0, 0, 0, 0], port))),
+        Some(bind) => bind.parse().ok().or_else(|| bind.parse().ok().map(|ip: IpAddr| SocketAddr::new(ip, port))),
+    }
+}
+
+fn arg_value(name: &str) -> Option<String> {
+    let args: Vec<String> = env::args().collect();
+    let pos = args.iter().position(|arg| arg == name)?;
+    args.get(pos + 1).cloned()
+}
+
+// Accepts clients until `shutdown` is set, then closes their read side so each one finishes the
+// command it is on and exits, and waits for them.
+fn serve(
+    listener: TcpListener,
+    ws_listener: Option<TcpListener>,
+    server: Arc<Server>,
+    shutdown: &AtomicBool,
+) -> io::Result<()> {
+    let listeners: Vec<(&TcpListener, bool)> =
+        std::iter::once((&listener, false)).chain(ws_listener.iter().map(|ws| (ws, true))).collect();
+    for (listener, _) in &listeners {
+        listener.set_nonblocking(true)?;
+    }
+    // Each client's socket, kept to shut it down on exit, and whether its session has ended.
+    let mut clients: Vec<(TcpStream, Arc<AtomicBool>)> = Vec::new();
+    let mut threads: Vec<thread::JoinHandle<()>> = Vec::new();
+    // With --workers, connections queue for a fixed set of threads instead of getting one each.
+    let pool = server.workers.map(|workers| {
+        let (jobs, queue) = mpsc::sync_channel::<Connection>(workers * QUEUE_PER_WORKER);
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..workers {
+            let (queue, server) = (Arc::clone(&queue), Arc::clone(&server));
+            threads.push(thread::spawn(move || {
+                loop {
+                    let job = lock(&queue).recv();
+                    let Ok((sock, websocket, done)) = job else {
+                        break;
+                    };
+                    handle_connection(sock, websocket, Arc::clone(&server));
+                    done.store(true, Ordering::SeqCst);
+                }
+            }));
+        }
+        jobs
+    });
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let mut accepted = false;
+        for &(listener, websocket) in &listeners {
+            match listener.accept() {
+                Ok((mut sock, _)) => {
+                    accepted = true;
+                    clients.retain(|(_, done)| !done.load(Ordering::SeqCst));
+                    if server.max_connections.is_some_and(|max| clients.len() >= max) {
+                        // Over the limit: say why where the protocol allows it, then hang up.
+                        if !websocket {
+                            let msg = server.error_reply(ErrorCode::ServerBusy, "Too many connections");
+                            let _ = sock.set_nonblocking(false).and_then(|_| server.framing.write(&mut sock, &msg));
+                        }
+                        continue;
+                    }
+                    match sock
+                        .set_nonblocking(false)
+                        .and_then(|_| sock.set_read_timeout(server.client_timeout))
+                        .and_then(|_| sock.try_clone())
+                    {
+                        Ok(watch) => {
+                            let done = Arc::new(AtomicBool::new(false));
+                            match &pool {
+                                Some(jobs) => match jobs.try_send((sock, websocket, Arc::clone(&done))) {
+                                    Ok(()) => clients.push((watch, done)),
+                                    Err(_) => {
+                                        if !websocket {
+                                            let msg = server.error_reply(ErrorCode::ServerBusy, "Server busy");
+                                            let _ = server.framing.write(&mut &watch, &msg);
+                                        }
+                                    }
+                                },
+                                None => {
+                                    let (server, finished) = (Arc::clone(&server), Arc::clone(&done));
+                                    threads.push(thread::spawn(move || {
+                                        handle_connection(sock, websocket, server);
+                                        finished.store(true, Ordering::SeqCst);
+                                    }));
+                                    clients.push((watch, done));
+                                }
+                            }
+                        }
+                        Err(e) => log!(Error, "Connection error: {}", e),
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => log!(Error, "Connection error: {}", e),
+            }
+        }
+        if !accepted {
+            thread::sleep(ACCEPT_POLL);
+        }
+        clients.retain(|(_, done)| !done.load(Ordering::SeqCst));
+        threads.retain(|handle| !handle.is_finished());
+    }
+
+    log!(Info, "Shutting down");
+    for (sock, _) in clients {
+        let _ = sock.shutdown(Shutdown::Read);
+    }
+    // Workers drain what's queued (every socket is shut by now) and stop once the queue closes.
+    drop(pool);
+    for handle in threads {
+        let _ = handle.join();
+    }
+    Ok(())
+}
+
+fn main() -> std::io::Result<()> {
+    let port = env::args()
+        .find_map(|arg| arg.strip_prefix("-p")?.parse::<u16>().ok())
+        .unwrap_or(54321);
+    let depth = env::args()
+        .find_map(|arg| arg.strip_prefix("-d")?.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_DEPTH);
+    let board_dimension = |flag: &str| {
+        env::args()
+            .find_map(|arg| arg.strip_prefix(flag)?.parse::<usize>().ok())
+            .filter(|size| (MIN_BOARD_SIZE..=MAX_BOARD_SIZE).contains(size))
+    };
+    // -b sets a square board; -w and -h override one side of it.
+    let size = board_dimension("-b").unwrap_or(DEFAULT_BOARD_SIZE);
+    let width = board_dimension("-w").unwrap_or(size);
+    let height = board_dimension("-h").unwrap_or(size);
+    let time_budget = env::args()
+        .find_map(|arg| arg.strip_prefix("-t")?.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_TIME_BUDGET_MS);
+    let radius = env::args()
+        .find_map(|arg| arg.strip_prefix("-r")?.parse::<usize>().ok())
+        .filter(|&radius| radius > 0)
+        .unwrap_or(DEFAULT_RADIUS);
+    // -v is short for --log debug.
+    let log_level = match arg_value("--log").as_deref() {
+        Some("error") => LogLevel::Error,
+        Some("info") => LogLevel::Info,
+        Some("debug") => LogLevel::Debug,
+        Some(level) => {
+            eprintln!("Unknown log level: {}", level);
+            std::process::exit(2);
+        }
+        None if env::args().any(|arg| arg == "-v") => LogLevel::Debug,
+        None => LogLevel::Info,
+    };
+    let _ = LOG_LEVEL.set(log_level);
+    FLAT_ERRORS.store(env::args().any(|arg| arg == "--flat-errors"), Ordering::Relaxed);
+    let print_board = env::args().any(|arg| arg == "--print-board");
+    let report_depth = env::args().any(|arg| arg == "--report-depth");
+    let gtp = env::args().any(|arg| arg == "-g");
+    let snapshot_dir = arg_value("--snapshot");
+    let state_dir = arg_value("--state-dir");
+    let team = arg_value("--team");
+    let client_timeout = arg_value("--client-timeout").and_then(|secs| secs.parse::<u64>().ok());
+    let session_ttl = arg_value("--session-ttl").and_then(|secs| secs.parse::<u64>().ok());
+    let max_connections = arg_value("--max-connections").and_then(|max| max.parse::<usize>().ok());
+    let workers = arg_value("--workers").and_then(|workers| workers.parse::<usize>().ok());
+    let rate = arg_value("--rate").and_then(|rate| rate.parse::<usize>().ok());
+    let max_moves = arg_value("--max-moves").and_then(|moves| moves.parse::<usize>().ok());
+    let weights_path = arg_value("--weights");
+    let style = arg_value("--style").map(|style| {
+        Weights::for_style(&style).unwrap_or_else(|| {
+            log!(Error, "Unknown style: {}", style);
+            std::process::exit(2);
+        })
+    });
+    let framing = arg_value("--framing").map(|framing| match framing.as_str() {
+        "newline" => Framing::Newline,
+        "length" => Framing::Length,
+        _ => {
+            log!(Error, "Unknown framing: {}", framing);
+            std::process::exit(2);
+        }
+    });
+    let book_path = arg_value("--book");
+    let batch_path = arg_value("--batch");
+    let allow_overline = arg_value("--allow-overline").and_then(|allow| allow.parse::<bool>().ok());
+    let renju = env::args().any(|arg| arg == "--renju");
+    let pente = env::args().any(|arg| arg == "--pente");
+    let center_bias = arg_value("--center-bias").and_then(|bias| bias.parse::<i32>().ok());
+    let seed = arg_value("--seed").and_then(|seed| seed.parse::<u64>().ok());
+    let deterministic = env::args().any(|arg| arg == "--deterministic");
+    let expand_below = arg_value("--expand-below").and_then(|threshold| threshold.parse::<i32>().ok());
+    let follow_plan = env::args().any(|arg| arg == "--follow-plan");
+    let strategy = arg_value("--strategy").map(|strategy| match strategy.as_str() {
+        "heuristic" => Strategy::Heuristic,
+        "mirror" => Strategy::Mirror,
+        _ => {
+            log!(Error, "Unknown strategy: {}", strategy);
+            std::process::exit(2);
+        }
+    });
+    if deterministic && seed.is_some() {
+        log!(Error, "--deterministic and --seed can't be used together");
+        std::process::exit(2);
+    }
+    let ws_port = arg_value("--ws-port").and_then(|port| port.parse::<u16>().ok());
+    let bind = arg_value("--bind");
+    let Some(addr) = bind_address(bind.as_deref(), port) else {
+        log!(Error, "Invalid bind address: {}", bind.unwrap_or_default());
+        std::process::exit(2);
+    };
+
+    let mut game = GameState::new(width, height);
+    game.search_depth = depth;
+    game.time_budget = Duration::from_millis(time_budget);
+    game.radius = radius;
+    game.expand_below = expand_below;
+    game.max_moves = max_moves;
+    game.allow_overline = allow_overline.unwrap_or(true);
+    game.renju = renju;
+    game.pente = pente;
+    game.center_bias = center_bias.unwrap_or(DEFAULT_CENTER_BIAS);
+    game.verbose = log_enabled(LogLevel::Debug);
+    game.print_board = print_board;
+    game.report_depth = report_depth;
+    game.state_dir = state_dir;
+    if let Some(weights) = style {
+        game.weights = weights;
+    }
+    if let Some(path) = weights_path {
+        game.weights = Weights::load(&path)?;
+    }
+    if let Some(path) = book_path {
+        game.book = Arc::new(Book::load(&path)?);
+    }
+    if let Some(seed) = seed {
+        game.rng = StdRng::seed_from_u64(seed);
+    }
+    game.deterministic = deterministic;
+    game.follow_plan = follow_plan;
+    if let Some(strategy) = strategy {
+        game.strategy = strategy;
+    }
+    let mut server = Server::new(game);
+    server.gtp = gtp;
+    server.client_timeout = client_timeout.filter(|&secs| secs > 0).map(Duration::from_secs);
+    server.session_ttl = session_ttl.filter(|&secs| secs > 0).map(Duration::from_secs);
+    server.max_connections = max_connections;
+    server.workers = workers.filter(|&workers| workers > 0);
+    server.rate = rate.filter(|&rate| rate > 0);
+    server.framing = framing.unwrap_or(Framing::Newline);
+    if let Some(team) = team {
+        server.team = team;
+    }
+    let server = Arc::new(server);
+
+    if let Some(path) = batch_path {
+        run_batch(&server, BufReader::new(fs::File::open(path)?), &mut io::stdout().lock())?;
+    } else {
+        let listener = TcpListener::bind(addr)?;
+        log!(Info, "Server running on {}", listener.local_addr()?);
+        let ws_listener = match ws_port {
+            Some(ws_port) => {
+                let ws_listener = TcpListener::bind(SocketAddr::new(addr.ip(), ws_port))?;
+                log!(Info, "WebSocket server running on {}", ws_listener.local_addr()?);
+                Some(ws_listener)
+            }
+            None => None,
+        };
+
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+        unsafe {
+            libc::signal(libc::SIGINT, request_shutdown as *const () as libc::sighandler_t);
+            libc::signal(libc::SIGTERM, request_shutdown as *const () as libc::sighandler_t);
+        }
+        serve(listener, ws_listener, Arc::clone(&server), &SHUTDOWN)?;
+    }
+
+    if let Some(dir) = snapshot_dir {
+        server.snapshot(&dir);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game(size: usize) -> GameState {
+        let mut game = GameState::new(size, size);
+        game.deterministic = true;
+        game
+    }
+
+    fn place(game: &mut GameState, cells: &[(usize, usize)], player: Player) {
+        for &(x, y) in cells {
+            game.place_stone(x, y, player);
+        }
+    }
+
+    // A game with the stones drawn row by row: X ours, O the opponent's.
+    fn board(rows: &[&str]) -> GameState {
+        let mut game = GameState::new(rows[0].len(), rows.len());
+        game.deterministic = true;
+        game.first_move = false;
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                match c {
+                    'X' => game.place_stone(x, y, Player::Me),
+                    'O' => game.place_stone(x, y, Player::Opponent),
+                    _ => {}
+                }
+            }
+        }
+        game
+    }
+
+    fn send(game: &mut GameState, json: &str) -> serde_json::Value {
+        let reply = process_command(game, serde_json::from_str(json).unwrap(), "team crabs");
+        serde_json::from_str(&reply).unwrap()
+    }
+
+    // A server on an ephemeral local port, shut down and joined when dropped.
+    struct Running {
+        addr: SocketAddr,
+        shutdown: Arc<AtomicBool>,
+        handle: Option<thread::JoinHandle<io::Result<()>>>,
+    }
+
+    impl Running {
+        fn start(server: Server) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let shutdown = Arc::new(AtomicBool::new(false));
+            let flag = Arc::clone(&shutdown);
+            let handle = thread::spawn(move || serve(listener, None, Arc::new(server), &flag));
+            Self { addr, shutdown, handle: Some(handle) }
+        }
+
+        fn connect(&self) -> Client {
+            let stream = TcpStream::connect(self.addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            Client { reader: BufReader::new(stream.try_clone().unwrap()), stream }
+        }
+    }
+
+    impl Drop for Running {
+        fn drop(&mut self) {
+            self.shutdown.store(true, Ordering::SeqCst);
+            if let Some(handle) = self.handle.take() {
+                handle.join().unwrap().unwrap();
+            }
+        }
+    }
+
+    struct Client {
+        stream: TcpStream,
+        reader: BufReader<TcpStream>,
+    }
+
+    impl Client {
+        fn send(&mut self, line: &str) {
+            writeln!(self.stream, "{}", line).unwrap();
+        }
+
+        fn reply(&mut self) -> Option<serde_json::Value> {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) | Err(_) => None,
+                Ok(_) => Some(serde_json::from_str(&line).unwrap()),
+            }
+        }
+
+        fn ask(&mut self, line: &str) -> serde_json::Value {
+            self.send(line);
+            self.reply().expect("connection closed")
+        }
+    }
+
+    #[test]
+    fn transpositions_cut_repeated_search() {
+        let mut game = game(15);
+        place(&mut game, &[(7, 7), (8, 8), (6, 8)], Player::Me);
+        place(&mut game, &[(8, 7), (7, 8), (9, 6)], Player::Opponent);
+        let first = game.minimax(3, -INF, INF, true);
+        let cold = game.nodes;
+        game.nodes = 0;
+        assert_eq!(game.minimax(3, -INF, INF, true), first);
+        assert!(game.nodes * 10 < cold, "{} nodes warm vs {} cold", game.nodes, cold);
+    }
+
+    #[test]
+    fn first_stone_goes_on_the_center() {
+        let mut game = game(15);
+        assert_eq!(game.center, (7, 7));
+        let reply = send(&mut game, r#"{"command":"start"}"#);
+        assert_eq!(reply["move"], serde_json::json!({"x": 7, "y": 7}));
+    }
+
+    #[test]
+    fn move_off_the_board_is_an_error() {
+        let mut game = game(DEFAULT_BOARD_SIZE);
+        send(&mut game, r#"{"command":"start"}"#);
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":31,"y":31}}"#);
+        assert_eq!(reply["error"]["code"], "OUT_OF_BOUNDS");
+        assert_eq!(game.history.len(), 1);
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":30,"y":30}}"#);
+        assert!(reply.get("move").is_some(), "{}", reply);
+    }
+
+    #[test]
+    fn bad_coordinate_keeps_the_listener_up() {
+        let running = Running::start(Server::new(game(15)));
+        let mut client = running.connect();
+        client.ask(r#"{"command":"start"}"#);
+        let reply = client.ask(r#"{"command":"move","opponentMove":{"x":"foo","y":3}}"#);
+        assert_eq!(reply["error"]["code"], "INVALID_COORDINATE");
+        let reply = client.ask(r#"{"command":"move","opponentMove":{"x":"8","y":"8"}}"#);
+        assert!(reply.get("move").is_some(), "{}", reply);
+        assert_eq!(running.connect().ask(r#"{"command":"ping"}"#)["reply"], "pong");
+    }
+
+    #[test]
+    fn vcf_finds_the_double_four() {
+        // Two blocked threes crossing at (6, 7): playing there makes two fours at once.
+        let mut game = game(15);
+        place(&mut game, &[(3, 7), (4, 7), (5, 7), (6, 4), (6, 5), (6, 6)], Player::Me);
+        place(&mut game, &[(2, 7), (6, 3), (10, 10)], Player::Opponent);
+        assert_eq!(game.winning_move(Player::Me), None);
+        let sequence = game.vcf_search(Player::Me, VCF_DEPTH).expect("no VCF found");
+        assert_eq!(sequence.len() % 2, 1);
+        for (i, &(x, y)) in sequence.iter().enumerate() {
+            game.place_stone(x, y, if i % 2 == 0 { Player::Me } else { Player::Opponent });
+        }
+        let &(x, y) = sequence.last().unwrap();
+        assert!(game.completes_five(x, y, Player::Me));
+    }
+
+    #[test]
+    fn sessions_keep_separate_boards() {
+        let server = Server::new(game(15));
+        thread::scope(|scope| {
+            for (session, x) in [("alice", 3), ("bob", 11)] {
+                let server = &server;
+                scope.spawn(move || {
+                    let line = |command: &str| format!(r#"{{"session":"{}",{}}}"#, session, command);
+                    process_line(server, None, &line(r#""command":"start""#));
+                    for y in [3, 5, 9] {
+                        let command = format!(r#""command":"move","opponentMove":{{"x":{},"y":{}}}"#, x, y);
+                        process_line(server, None, &line(&command));
+                    }
+                });
+            }
+        });
+        for (session, x) in [("alice", 3), ("bob", 11)] {
+            let state = server.session(session);
+            let game = lock(&state);
+            assert_eq!(game.history.len(), 7);
+            let theirs = game.stones(Player::Opponent);
+            assert_eq!(theirs.len(), 3);
+            assert!(theirs.iter().all(|&(ox, _)| ox == x), "{:?}", theirs);
+        }
+    }
+
+    #[test]
+    fn tiny_budget_still_moves() {
+        let mut game = game(DEFAULT_BOARD_SIZE);
+        game.time_budget = Duration::from_millis(1);
+        let mine = [(15, 15), (16, 16), (14, 16), (17, 14), (13, 13), (16, 13), (12, 17)];
+        let theirs = [(15, 16), (16, 15), (14, 14), (17, 17), (13, 15), (15, 13), (18, 16)];
+        for (&a, &b) in mine.iter().zip(&theirs) {
+            place(&mut game, &[a], Player::Me);
+            place(&mut game, &[b], Player::Opponent);
+        }
+        let (x, y) = game.find_best_move().expect("no move");
+        assert!(game.move_legality(x, y, Player::Me).is_ok());
+        assert_eq!(game.deadline, None);
+    }
+
+    #[test]
+    fn forced_win_searches_stop_at_the_deadline() {
+        let mut game = game(15);
+        place(&mut game, &[(3, 7), (4, 7), (5, 7), (6, 4), (6, 5), (6, 6)], Player::Me);
+        place(&mut game, &[(2, 7), (6, 3), (10, 10)], Player::Opponent);
+        assert!(game.vcf_search(Player::Me, VCF_DEPTH).is_some());
+        assert!(game.vct_move(Player::Me, VCT_DEPTH).is_some());
+        game.deadline = Some(Instant::now());
+        assert!(game.vcf_search(Player::Me, VCF_DEPTH).is_none());
+        assert!(game.vct_move(Player::Me, VCT_DEPTH).is_none());
+    }
+
+    #[test]
+    fn fours_against_the_edge_are_blocked() {
+        let mut game = game(15);
+        place(&mut game, &[(0, 0), (1, 0), (2, 0), (3, 0)], Player::Me);
+        place(&mut game, &[(0, 5), (0, 6), (0, 7), (0, 8)], Player::Me);
+        place(&mut game, &[(14, 11), (14, 12), (14, 13), (14, 14)], Player::Me);
+        assert_eq!(game.evaluate_line_type(0, 0, 1, 0, Player::Me), LineThreat::BlockedFour);
+        assert_eq!(game.evaluate_line_type(3, 0, 1, 0, Player::Me), LineThreat::BlockedFour);
+        assert_eq!(game.evaluate_line_type(0, 0, 0, 1, Player::Me), LineThreat::Other);
+        assert_eq!(game.evaluate_line_type(0, 5, 0, 1, Player::Me), LineThreat::OpenFour);
+        assert_eq!(game.evaluate_line_type(14, 14, 0, 1, Player::Me), LineThreat::BlockedFour);
+        game.place_stone(0, 4, Player::Opponent);
+        assert_eq!(game.evaluate_line_type(0, 8, 0, 1, Player::Me), LineThreat::BlockedFour);
+    }
+
+    #[test]
+    fn swap2_negotiation() {
+        let mut game = game(15);
+        let reply = send(&mut game, r#"{"command":"swap2_propose"}"#);
+        assert_eq!(reply["stones"].as_array().unwrap().len(), 3);
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":1,"y":1}}"#);
+        assert_eq!(reply["error"]["code"], "SWAP2_PENDING");
+        let reply = send(&mut game, r#"{"command":"swap2_decide","choice":"place2","stones":[{"x":7,"y":7}]}"#);
+        assert_eq!(reply["error"]["code"], "INVALID_SWAP2");
+
+        let stones = r#"[{"x":6,"y":8},{"x":9,"y":9}]"#;
+        let reply = send(&mut game, &format!(r#"{{"command":"swap2_decide","choice":"place2","stones":{}}}"#, stones));
+        assert!(game.opening.is_empty());
+        let moved = match reply["color"].as_str() {
+            Some("white") => {
+                assert!(reply["move"].is_object(), "{}", reply);
+                6
+            }
+            Some("black") => {
+                let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":3,"y":3}}"#);
+                assert!(reply["move"].is_object(), "{}", reply);
+                7
+            }
+            _ => panic!("{}", reply),
+        };
+        assert_eq!(game.history.len(), moved);
+    }
+
+    #[test]
+    fn swap2_taking_black_leaves_us_to_move() {
+        let mut game = game(15);
+        send(&mut game, r#"{"command":"swap2_propose"}"#);
+        let reply = send(&mut game, r#"{"command":"swap2_decide","choice":"black"}"#);
+        assert_eq!(reply["color"], "white");
+        assert!(reply["move"].is_object(), "{}", reply);
+        assert_eq!(game.stones(Player::Me).len(), 2);
+        assert_eq!(game.stones(Player::Opponent).len(), 2);
+    }
+
+    #[test]
+    fn parallel_scoring_matches_serial() {
+        let mut game = game(DEFAULT_BOARD_SIZE);
+        place(&mut game, &[(15, 15), (16, 16), (14, 16), (17, 14), (13, 13)], Player::Me);
+        place(&mut game, &[(15, 16), (16, 15), (14, 14), (17, 17), (13, 15)], Player::Opponent);
+        let mut serial: Vec<_> = game
+            .canonical_candidates(game.radius)
+            .iter()
+            .map(|&(x, y)| (game.score_cell(x, y), (x, y)))
+            .collect();
+        serial.sort_by_key(|&(score, _)| std::cmp::Reverse(score));
+        serial.truncate(MAX_CANDIDATES);
+        let serial: Vec<_> = serial.into_iter().map(|(_, cell)| cell).collect();
+        assert_eq!(game.search_candidates(), serial);
+        for cell in game.heatmap() {
+            assert_eq!(cell.score, game.score_cell(cell.x, cell.y));
+        }
+    }
+
+    #[test]
+    fn save_and_load_stay_in_the_state_directory() {
+        let mut game = game(15);
+        assert_eq!(send(&mut game, r#"{"command":"save","path":"game"}"#)["error"]["code"], "INVALID_PATH");
+
+        let dir = env::temp_dir().join(format!("rbot-state-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        game.state_dir = Some(dir.to_string_lossy().into_owned());
+        send(&mut game, r#"{"command":"start"}"#);
+        send(&mut game, r#"{"command":"move","opponentMove":{"x":8,"y":8}}"#);
+        let history = game.history.clone();
+        assert_eq!(send(&mut game, r#"{"command":"save","path":"../../game"}"#)["reply"], "ok");
+        assert!(dir.join("game.json").exists());
+        assert_eq!(send(&mut game, r#"{"command":"save","path":"../.."}"#)["error"]["code"], "INVALID_PATH");
+
+        send(&mut game, r#"{"command":"reset"}"#);
+        assert_eq!(send(&mut game, r#"{"command":"load","path":"game"}"#)["reply"], "ok");
+        assert_eq!(game.history, history);
+        assert_eq!(game.state_dir.as_deref(), Some(&*dir.to_string_lossy()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn undo_takes_back_whole_moves() {
+        let mut game = game(15);
+        send(&mut game, r#"{"command":"start"}"#);
+        let (bits, zobrist, score) = (game.my_bits.clone(), game.zobrist, game.board_score);
+        send(&mut game, r#"{"command":"move","opponentMove":{"x":8,"y":8}}"#);
+        assert_eq!(game.history.len(), 3);
+        let reply = send(&mut game, r#"{"command":"undo","n":2}"#);
+        assert_eq!((reply["undone"].as_u64(), reply["moves"].as_u64()), (Some(2), Some(1)));
+        assert_eq!((game.my_bits.clone(), game.zobrist, game.board_score), (bits, zobrist, score));
+        assert!(game.opponent_bits.iter().all(|&row| row == 0));
+        assert!(send(&mut game, r#"{"command":"move","opponentMove":{"x":8,"y":8}}"#)["move"].is_object());
+        assert_eq!(send(&mut game, r#"{"command":"undo","n":10}"#)["undone"], 3);
+        assert!(game.first_move);
+    }
+
+    #[test]
+    fn vertical_five_wins() {
+        let mut game = game(15);
+        game.first_move = false;
+        place(&mut game, &[(5, 3), (5, 4), (5, 5), (5, 6)], Player::Me);
+        place(&mut game, &[(9, 3), (9, 4), (9, 5)], Player::Opponent);
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":9,"y":6}}"#);
+        assert_eq!(reply["winner"], "team crabs");
+        let (x, y) = (reply["move"]["x"].as_u64().unwrap() as usize, reply["move"]["y"].as_u64().unwrap() as usize);
+        assert!(x == 5 && (y == 2 || y == 7), "{}", reply);
+        assert!(game.completes_five(x, y, Player::Me));
+
+        let mut game = GameState::new(15, 15);
+        game.first_move = false;
+        place(&mut game, &[(9, 3), (9, 4), (9, 5), (9, 6)], Player::Opponent);
+        place(&mut game, &[(5, 3), (5, 4), (6, 6)], Player::Me);
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":9,"y":7}}"#);
+        assert_eq!(reply["winner"], "opponent");
+    }
+
+    #[test]
+    fn split_shapes() {
+        let classify = |line: &str| GameState::classify_line(&line.chars().collect::<Vec<_>>(), Player::Me);
+        assert_eq!(classify("B.X.X.X.B"), LineThreat::Split);
+        assert_eq!(classify("B..XX.X.B"), LineThreat::Split);
+        assert_eq!(classify("BXX..XX.B"), LineThreat::Split);
+        assert_eq!(classify("BBXX.X.BB"), LineThreat::BrokenThree);
+
+        let mut game = game(15);
+        place(&mut game, &[(3, 3), (5, 3), (7, 3)], Player::Me);
+        assert_eq!(game.evaluate_line_type(5, 3, 1, 0, Player::Me), LineThreat::Split);
+        place(&mut game, &[(3, 6), (4, 6), (6, 6)], Player::Me);
+        assert_eq!(game.evaluate_line_type(4, 6, 1, 0, Player::Me), LineThreat::Split);
+        place(&mut game, &[(3, 9), (4, 9), (7, 9), (8, 9)], Player::Me);
+        assert_eq!(game.evaluate_line_type(4, 9, 1, 0, Player::Me), LineThreat::Split);
+    }
+
+    #[test]
+    fn no_reply_on_a_full_board_rolls_back() {
+        // The last cell left to us, (3, 0), would be a black overline under renju.
+        let mut game = board(&["XXX.XX", "XXOXOO", "OOXXOX", "OOXOOX", "XOXXXX", "OXOXX."]);
+        game.renju = true;
+        let (history, bits, zobrist, score) =
+            (game.history.clone(), game.opponent_bits.clone(), game.zobrist, game.board_score);
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":5,"y":5}}"#);
+        assert_eq!(reply["error"]["code"], "NO_MOVE_FOUND");
+        assert_eq!(game.history, history);
+        assert_eq!((game.opponent_bits.clone(), game.zobrist, game.board_score), (bits, zobrist, score));
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":5,"y":5}}"#);
+        assert_eq!(reply["error"]["code"], "NO_MOVE_FOUND");
+    }
+
+    #[test]
+    fn gtp_play_genmove_and_clear_board() {
+        let mut game = game(15);
+        assert_eq!(handle_gtp(&mut game, "play white 7 7"), "=\n");
+        assert_eq!(handle_gtp(&mut game, "play white 7 7"), "? illegal move\n");
+        assert_eq!(handle_gtp(&mut game, "play purple 1 1"), "? invalid color\n");
+        let reply = handle_gtp(&mut game, "genmove black");
+        let cell: Vec<usize> = reply.trim_start_matches("= ").split_whitespace().map(|n| n.parse().unwrap()).collect();
+        assert_eq!(game.stone_at(cell[0], cell[1]), Some(Player::Me));
+        assert_eq!(game.stone_at(7, 7), Some(Player::Opponent));
+        assert_eq!(handle_gtp(&mut game, "clear_board"), "=\n");
+        assert!(game.history.is_empty() && game.first_move);
+        assert_eq!(handle_gtp(&mut game, "showboard"), "? unknown command\n");
+    }
+
+    #[test]
+    fn starting_second_answers_next_to_the_opening_stone() {
+        let mut game = game(15);
+        assert_eq!(send(&mut game, r#"{"command":"start","first":false}"#)["reply"], "ready");
+        assert_eq!(game.my_color, Color::White);
+        assert!(game.history.is_empty());
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":7,"y":7}}"#);
+        let (x, y) = (reply["move"]["x"].as_u64().unwrap(), reply["move"]["y"].as_u64().unwrap());
+        assert_eq!(x.abs_diff(7).max(y.abs_diff(7)), 1, "{}", reply);
+        assert_eq!(send(&mut game, r#"{"command":"start","first":false}"#)["error"]["code"], "NOT_FIRST_MOVE");
+
+        // The reply is the search's, off the center as well.
+        let mut off_center = GameState::new(15, 15);
+        place(&mut off_center, &[(3, 12)], Player::Opponent);
+        let (cell, info) = off_center.find_best_move_with_info();
+        let (x, y) = cell.unwrap();
+        assert_eq!((x.abs_diff(3).max(y.abs_diff(12)), info.reason), (1, "search"));
+    }
+
+    #[test]
+    fn killers_cut_nodes() {
+        let mut with = game(15);
+        place(&mut with, &[(7, 7), (8, 8), (6, 8), (9, 6)], Player::Me);
+        place(&mut with, &[(8, 7), (7, 8), (6, 6), (10, 5)], Player::Opponent);
+        let mut without = with.clone();
+        without.use_killers = false;
+        for game in [&mut with, &mut without] {
+            game.minimax(3, -INF, INF, true);
+        }
+        assert!(with.nodes < without.nodes, "{} nodes with killers, {} without", with.nodes, without.nodes);
+    }
+
+    #[test]
+    fn incremental_score_matches_full_rescore() {
+        let mut game = game(15);
+        let mut rng = StdRng::seed_from_u64(23);
+        let mut player = Player::Me;
+        while game.history.len() < 20 {
+            let (x, y) = (rng.gen_range(3..12), rng.gen_range(3..12));
+            if game.is_empty(x, y) {
+                game.place_stone(x, y, player);
+                assert_eq!(game.board_score, game.full_rescore());
+                player = player.other();
+            }
+        }
+        while !game.history.is_empty() {
+            game.undo_stone();
+            assert_eq!(game.board_score, game.full_rescore());
+        }
+        assert_eq!(game.board_score, 0);
+    }
+
+    #[test]
+    fn shutdown_flag_stops_the_server() {
+        let mut running = Running::start(Server::new(game(15)));
+        let mut client = running.connect();
+        assert_eq!(client.ask(r#"{"command":"ping"}"#)["reply"], "pong");
+        running.shutdown.store(true, Ordering::SeqCst);
+        let handle = running.handle.take().unwrap();
+        let started = Instant::now();
+        while !handle.is_finished() {
+            assert!(started.elapsed() < Duration::from_secs(5), "server still running");
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.join().unwrap().unwrap();
+        assert!(client.reply().is_none());
+        assert!(TcpStream::connect(running.addr).is_err());
+    }
+
+    #[test]
+    fn completing_five_scores_highest() {
+        let mut game = game(15);
+        place(&mut game, &[(3, 3), (4, 3), (5, 3), (6, 3), (9, 9), (9, 10)], Player::Me);
+        place(&mut game, &[(2, 3), (6, 6), (7, 6), (8, 6), (7, 8), (8, 9)], Player::Opponent);
+        let best = game.heatmap().into_iter().max_by_key(|cell| cell.score).unwrap();
+        assert_eq!((best.x, best.y), (7, 3));
+        assert!(game.completes_five(7, 3, Player::Me));
+    }
+
+    #[test]
+    fn takes_the_opponent_fork_point() {
+        let mut game = game(15);
+        place(&mut game, &[(12, 12), (2, 12)], Player::Me);
+        place(&mut game, &[(5, 7), (6, 7), (7, 5), (7, 6)], Player::Opponent);
+        assert_eq!(game.opponent_fork_cells(), vec![(7, 7)]);
+        let (cell, info) = game.find_best_move_with_info();
+        assert_eq!((cell, info.reason), (Some((7, 7)), "fork"));
+    }
+
+    #[test]
+    fn replies_carry_the_configured_team() {
+        let mut server = Server::new(game(15));
+        server.team = "team sharks".into();
+        let (reply, _) = process_line(&server, None, r#"{"command":"start"}"#);
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply["team"], "team sharks");
+        let (reply, _) = process_line(&server, None, r#"{"command":"move","opponentMove":{"x":8,"y":8}}"#);
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply["team"], "team sharks");
+    }
+
+    #[test]
+    fn rebuilding_from_history_gives_the_same_board() {
+        let mut game = game(15);
+        place(&mut game, &[(7, 7), (8, 8), (6, 8), (9, 6), (5, 5)], Player::Me);
+        place(&mut game, &[(8, 7), (7, 8), (6, 6), (10, 5), (4, 4)], Player::Opponent);
+        let mut replayed: GameState = serde_json::from_str(&serde_json::to_string(&game).unwrap()).unwrap();
+        assert!(replayed.my_bits.is_empty());
+        replayed.rebuild();
+        assert_eq!(replayed.my_bits, game.my_bits);
+        assert_eq!(replayed.opponent_bits, game.opponent_bits);
+        assert_eq!(replayed.zobrist, game.zobrist);
+        assert_eq!(replayed.symmetric_zobrist, game.symmetric_zobrist);
+        assert_eq!(replayed.board_score, game.board_score);
+    }
+
+    #[test]
+    fn hint_leaves_the_board_alone() {
+        let mut game = game(15);
+        send(&mut game, r#"{"command":"start"}"#);
+        send(&mut game, r#"{"command":"move","opponentMove":{"x":8,"y":8}}"#);
+        let (history, zobrist, score) = (game.history.clone(), game.zobrist, game.board_score);
+        let hint = send(&mut game, r#"{"command":"hint"}"#);
+        let (x, y) = (hint["hint"]["x"].as_u64().unwrap() as usize, hint["hint"]["y"].as_u64().unwrap() as usize);
+        assert!(game.is_empty(x, y));
+        assert_eq!((game.history.clone(), game.zobrist, game.board_score), (history, zobrist, score));
+        assert_eq!(send(&mut game, r#"{"command":"hint"}"#), hint);
+    }
+
+    #[test]
+    fn membership_checks_do_not_grow_with_the_game() {
+        let time_checks = |game: &GameState| {
+            (0..5)
+                .map(|_| {
+                    let started = Instant::now();
+                    for i in 0..200_000 {
+                        let (x, y) = (i % game.width, i / game.width % game.height);
+                        std::hint::black_box(game.is_my_move(x, y) || game.is_opponent_move(x, y));
+                    }
+                    started.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+        let mut game = game(DEFAULT_BOARD_SIZE);
+        place(&mut game, &[(15, 15)], Player::Me);
+        let sparse = time_checks(&game);
+        for i in 0..400 {
+            let (x, y) = (i % DEFAULT_BOARD_SIZE, i / DEFAULT_BOARD_SIZE + 16);
+            game.place_stone(x, y, Player::from_is_my(i % 2 == 0));
+        }
+        let crowded = time_checks(&game);
+        assert!(crowded < sparse * 3, "{:?} with 401 stones vs {:?} with one", crowded, sparse);
+    }
+
+    #[test]
+    fn candidates_stay_near_the_stones() {
+        let mut game = game(DEFAULT_BOARD_SIZE);
+        assert_eq!(game.candidate_cells(), vec![game.center]);
+        place(&mut game, &[(10, 20)], Player::Opponent);
+        let cells = game.candidate_cells();
+        assert_eq!(cells.len(), 24);
+        assert!(cells.contains(&(11, 20)) && cells.contains(&(9, 19)));
+        assert!(cells.iter().all(|&(x, y)| x.abs_diff(10).max(y.abs_diff(20)) <= 2));
+        game.radius = 1;
+        assert_eq!(game.candidate_cells().len(), 8);
+    }
+
+    #[test]
+    fn same_seed_plays_the_same_game() {
+        let seeded = || {
+            let mut game = GameState::new(15, 15);
+            game.search_depth = 2;
+            game.rng = StdRng::seed_from_u64(32);
+            game
+        };
+        let (mut a, mut b) = (seeded(), seeded());
+        send(&mut a, r#"{"command":"start"}"#);
+        send(&mut b, r#"{"command":"start"}"#);
+        for (x, y) in [(1, 1), (13, 1), (1, 13), (13, 13), (2, 7)] {
+            if !a.is_empty(x, y) {
+                continue;
+            }
+            let line = format!(r#"{{"command":"move","opponentMove":{{"x":{},"y":{}}}}}"#, x, y);
+            assert_eq!(send(&mut a, &line), send(&mut b, &line));
+        }
+        assert_eq!(a.history, b.history);
+    }
+
+    #[test]
+    fn opponent_move_on_an_occupied_cell() {
+        let mut game = game(15);
+        send(&mut game, r#"{"command":"start"}"#);
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":7,"y":7}}"#);
+        assert_eq!(reply["error"]["code"], "OCCUPIED");
+        assert_eq!(game.history.len(), 1);
+
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":9,"y":9}}"#);
+        let history = game.history.clone();
+        assert_eq!(send(&mut game, r#"{"command":"move","opponentMove":{"x":9,"y":9}}"#), reply);
+        assert_eq!(game.history, history);
+        // Only the latest move can be resent.
+        send(&mut game, r#"{"command":"move","opponentMove":{"x":2,"y":2}}"#);
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":9,"y":9}}"#);
+        assert_eq!(reply["error"]["code"], "OCCUPIED");
+    }
+
+    #[test]
+    fn stats_count_the_moves() {
+        let mut game = game(15);
+        send(&mut game, r#"{"command":"start"}"#);
+        send(&mut game, r#"{"command":"move","opponentMove":{"x":9,"y":9}}"#);
+        let stats = send(&mut game, r#"{"command":"stats"}"#);
+        assert_eq!(stats["moves"], 3);
+        assert_eq!(stats["fill"].as_f64(), Some(3.0 / 225.0));
+        assert!(stats["last_nodes"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn rectangular_board() {
+        let mut game = GameState::new(20, 15);
+        assert_eq!(send(&mut game, r#"{"command":"start"}"#)["move"], serde_json::json!({"x": 10, "y": 7}));
+        place(&mut game, &[(15, 3), (16, 3), (17, 3), (18, 3)], Player::Me);
+        place(&mut game, &[(14, 3)], Player::Opponent);
+        assert_eq!(game.evaluate_line_type(18, 3, 1, 0, Player::Me), LineThreat::BlockedFour);
+        assert!(game.completes_five(19, 3, Player::Me));
+        let best = game.heatmap().into_iter().max_by_key(|cell| cell.score).unwrap();
+        assert_eq!((best.x, best.y), (19, 3));
+        assert!(!game.in_bounds(20, 3) && game.in_bounds(19, 14) && !game.in_bounds(19, 15));
+        game.place_stone(19, 3, Player::Me);
+        assert!(game.completes_five(19, 3, Player::Me));
+    }
+
+    #[test]
+    fn silent_client_is_dropped() {
+        let mut server = Server::new(game(15));
+        server.client_timeout = Some(Duration::from_millis(200));
+        let running = Running::start(server);
+        let mut client = running.connect();
+        assert_eq!(client.ask(r#"{"command":"ping"}"#)["reply"], "pong");
+        let started = Instant::now();
+        assert!(client.reply().is_none());
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(150) && waited < Duration::from_secs(5), "{:?}", waited);
+    }
+
+    #[test]
+    fn principal_variation_of_a_forced_win_ends_in_five() {
+        let ends_in_five = |game: &GameState, pv: &[(usize, usize)]| {
+            let mut game = game.clone();
+            for (i, &(x, y)) in pv.iter().enumerate() {
+                game.place_stone(x, y, if i % 2 == 0 { Player::Me } else { Player::Opponent });
+            }
+            pv.len() % 2 == 1 && pv.last().is_some_and(|&(x, y)| game.completes_five(x, y, Player::Me))
+        };
+
+        let mut game = game(15);
+        place(&mut game, &[(3, 7), (4, 7), (5, 7), (6, 4), (6, 5), (6, 6)], Player::Me);
+        place(&mut game, &[(2, 7), (6, 3), (10, 10)], Player::Opponent);
+        let reply = send(&mut game, r#"{"command":"analyze"}"#);
+        let pv: Vec<(usize, usize)> = reply["pv"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|cell| (cell["x"].as_u64().unwrap() as usize, cell["y"].as_u64().unwrap() as usize))
+            .collect();
+        assert!(ends_in_five(&game, &pv), "{}", reply);
+
+        // An open three, found by the search itself.
+        let mut game = GameState::new(15, 15);
+        place(&mut game, &[(5, 7), (6, 7), (7, 7)], Player::Me);
+        place(&mut game, &[(5, 8), (6, 9)], Player::Opponent);
+        let root = game.search_root(3, (-INF, INF)).unwrap();
+        assert!(root.best_score >= WIN_SCORE);
+        let pv = game.principal_variation(root.best_moves[0]);
+        assert!(ends_in_five(&game, &pv), "{:?}", pv);
+    }
+
+    #[test]
+    fn symmetric_positions_keep_one_move_per_orbit() {
+        let mut game = game(15);
+        assert_eq!(game.canonical_candidates(2), vec![(7, 7)]);
+        place(&mut game, &[(7, 7)], Player::Me);
+        let distinct = game.canonical_candidates(2);
+        assert_eq!(distinct.len(), 5);
+        for (x, y) in game.cells_within(2) {
+            assert!((0..8).any(|k| distinct.contains(&game.transform(k, x, y))), "({}, {})", x, y);
+        }
+        place(&mut game, &[(8, 6)], Player::Opponent);
+        place(&mut game, &[(9, 9)], Player::Me);
+        assert_eq!(game.canonical_candidates(2), game.cells_within(2));
+    }
+
+    #[test]
+    fn filling_the_board_is_a_draw() {
+        let mut game = board(&["OXXOO", "XXOOX", "OXOXX", "OOOXX", "XOOO."]);
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":4,"y":4}}"#);
+        assert_eq!(reply["result"], "draw");
+        assert!(game.is_draw());
+    }
+
+    #[test]
+    fn move_cap_is_a_draw() {
+        let mut game = game(15);
+        game.max_moves = Some(3);
+        send(&mut game, r#"{"command":"start"}"#);
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":9,"y":9}}"#);
+        assert_eq!(reply["result"], "draw");
+        assert!(reply["move"].is_object());
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":2,"y":2}}"#);
+        assert_eq!(reply["result"], "draw");
+    }
+
+    #[test]
+    fn custom_weights_change_the_move() {
+        let setup = |weights: Weights| {
+            let mut game = game(15);
+            game.search_depth = 2;
+            game.weights = weights;
+            place(&mut game, &[(5, 7), (6, 7), (7, 7)], Player::Me);
+            place(&mut game, &[(4, 7), (8, 9), (8, 10), (8, 11)], Player::Opponent);
+            game
+        };
+        // Our blocked three against their open three: by default we block it.
+        assert_eq!(setup(Weights::default()).find_best_move(), Some((8, 8)));
+        let mut careless = Weights::default();
+        careless.opponent =
+            ThreatWeights { open_three: 1, split: 1, two: 1, broken_three: 1, blocked_three: 1, ..careless.opponent };
+        let mut game = setup(careless);
+        assert!(game.evaluate_position() > setup(Weights::default()).evaluate_position());
+        assert_eq!(game.find_best_move(), Some((8, 7)));
+    }
+
+    #[test]
+    fn gap_four_has_to_be_answered() {
+        let mut game = game(15);
+        place(&mut game, &[(7, 7), (3, 12)], Player::Me);
+        place(&mut game, &[(3, 4), (4, 4), (6, 4), (7, 4)], Player::Opponent);
+        assert_eq!(game.evaluate_line_type(3, 4, 1, 0, Player::Opponent), LineThreat::GapFour);
+        assert_eq!(game.winning_move(Player::Opponent), Some((5, 4)));
+        let (cell, info) = game.find_best_move_with_info();
+        assert_eq!((cell, info.reason), (Some((5, 4)), "block"));
+    }
+
+    #[test]
+    fn every_command_replies() {
+        let mut game = game(9);
+        game.search_depth = 1;
+        let commands = [
+            (r#"{"command":"start"}"#, "move"),
+            (r#"{"command":"move","opponentMove":{"x":5,"y":5}}"#, "move"),
+            (r#"{"command":"board"}"#, "my"),
+            (r#"{"command":"heatmap"}"#, "cells"),
+            (r#"{"command":"hint"}"#, "hint"),
+            (r#"{"command":"refute","opponentMove":{"x":0,"y":8}}"#, "refutation"),
+            (r#"{"command":"analyze"}"#, "best_move"),
+            (r#"{"command":"eval"}"#, "score"),
+            (r#"{"command":"threats","move":{"x":4,"y":3}}"#, "directions"),
+            (r#"{"command":"threat_list"}"#, "threats"),
+            (r#"{"command":"is_legal","move":{"x":8,"y":0}}"#, "legal"),
+            (r#"{"command":"remaining"}"#, "empty"),
+            (r#"{"command":"stats"}"#, "moves"),
+            (r#"{"command":"timing"}"#, "under_1ms"),
+            (r#"{"command":"export"}"#, "sgf"),
+            (r#"{"command":"selfplay"}"#, "result"),
+            (r#"{"command":"undo"}"#, "undone"),
+            (r#"{"command":"set_color","color":"black"}"#, "color"),
+            (r#"{"command":"force","move":{"x":8,"y":8}}"#, "move"),
+            (r#"{"command":"save","path":"game"}"#, "error"),
+            (r#"{"command":"load","path":"game"}"#, "error"),
+            (r#"{"command":"load_game","moves":[{"x":4,"y":4},{"x":5,"y":5}]}"#, "reply"),
+            (r#"{"command":"new_match","n":2}"#, "reply"),
+            (r#"{"command":"match_status"}"#, "games"),
+            (r#"{"command":"reset"}"#, "reply"),
+            (r#"{"command":"swap2_propose"}"#, "stones"),
+            (r#"{"command":"swap2_decide","choice":"white"}"#, "color"),
+            (r#"{"command":"quit"}"#, "reply"),
+            (r#"{"command":"resign"}"#, "error"),
+        ];
+        for (command, key) in commands {
+            let reply = send(&mut game, command);
+            assert_eq!(reply["version"], PROTOCOL_VERSION, "{}", command);
+            assert!(reply.get(key).is_some(), "{} -> {}", command, reply);
+        }
+    }
+
+    #[test]
+    fn process_command_start_move_reset_unknown() {
+        let mut game = game(15);
+        let reply = send(&mut game, r#"{"command":"start"}"#);
+        assert_eq!(reply, serde_json::json!({"version": 1, "move": {"x": 7, "y": 7}, "team": "team crabs"}));
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":8,"y":8}}"#);
+        assert_eq!(reply["team"], "team crabs");
+        assert_eq!(game.history.len(), 3);
+        assert_eq!(send(&mut game, r#"{"command":"reset"}"#)["reply"], "ok");
+        assert!(game.history.is_empty() && game.first_move);
+        let reply = send(&mut game, r#"{"command":"dance"}"#);
+        assert_eq!(reply["error"], serde_json::json!({"code": "UNKNOWN_COMMAND", "message": "Unknown command"}));
+    }
+
+    #[test]
+    fn defends_against_an_opponent_vct() {
+        let mut game = game(15);
+        place(&mut game, &[(9, 4), (5, 9), (8, 7)], Player::Me);
+        place(&mut game, &[(7, 7), (8, 8), (6, 5), (5, 8)], Player::Opponent);
+        assert!(game.opponent_fork_cells().is_empty());
+        assert!(game.opponent_has_forced_win(VCT_DEPTH));
+        let (cell, info) = game.find_best_move_with_info();
+        assert_eq!(info.reason, "vct");
+        let (x, y) = cell.unwrap();
+        game.place_stone(x, y, Player::Me);
+        assert!(!game.opponent_has_forced_win(VCT_DEPTH));
+    }
+
+    #[test]
+    fn load_game_replays_the_moves() {
+        let mut game = game(15);
+        let moves = r#"[{"x":7,"y":7},{"x":"8","y":"8"},{"x":6,"y":6}]"#;
+        let reply = send(&mut game, &format!(r#"{{"command":"load_game","moves":{}}}"#, moves));
+        assert_eq!(reply["reply"], "ok");
+        assert_eq!(game.stones(Player::Opponent), vec![(6, 6), (7, 7)]);
+        assert_eq!(game.stones(Player::Me), vec![(8, 8)]);
+        assert_eq!((game.my_color, game.first_move), (Color::White, false));
+
+        let moves = r#"[{"x":7,"y":7},{"x":3,"y":3,"side":"me"}]"#;
+        let reply = send(&mut game, &format!(r#"{{"command":"load_game","first":true,"moves":{}}}"#, moves));
+        assert_eq!(reply["reply"], "ok");
+        assert_eq!(game.stones(Player::Me), vec![(3, 3), (7, 7)]);
+        assert_eq!(game.my_color, Color::Black);
+
+        let history = game.history.clone();
+        let reply = send(&mut game, r#"{"command":"load_game","moves":[{"x":1,"y":1},{"x":1,"y":1}]}"#);
+        assert_eq!(reply["error"]["message"], "Illegal move at index 1");
+        assert_eq!(game.history, history);
+    }
+
+    #[test]
+    fn either_side_blocks_the_others_four() {
+        let mut game = game(15);
+        place(&mut game, &[(3, 5), (4, 5), (5, 5), (6, 5)], Player::Me);
+        place(&mut game, &[(3, 9), (4, 9), (5, 9), (6, 9)], Player::Opponent);
+        assert_eq!(game.evaluate_line_type(3, 5, 1, 0, Player::Me), LineThreat::OpenFour);
+        assert_eq!(game.evaluate_line_type(3, 9, 1, 0, Player::Opponent), LineThreat::OpenFour);
+        place(&mut game, &[(7, 5)], Player::Opponent);
+        place(&mut game, &[(2, 9)], Player::Me);
+        assert_eq!(game.evaluate_line_type(3, 5, 1, 0, Player::Me), LineThreat::BlockedFour);
+        assert_eq!(game.evaluate_line_type(3, 9, 1, 0, Player::Opponent), LineThreat::BlockedFour);
+        assert_eq!(game.winning_move(Player::Me), Some((2, 5)));
+        assert_eq!(game.winning_move(Player::Opponent), Some((7, 9)));
+    }
+
+    #[test]
+    fn ties_go_to_the_most_forcing_move() {
+        let mut game = game(15);
+        place(&mut game, &[(5, 7), (6, 7), (10, 3), (10, 4), (10, 5)], Player::Me);
+        place(&mut game, &[(10, 2)], Player::Opponent);
+        let mut moves = vec![(1, 13), (7, 7), (10, 6)];
+        game.keep_most_forcing(&mut moves);
+        assert_eq!(moves, vec![(10, 6)]);
+        let mut moves = vec![(1, 13), (7, 7)];
+        game.keep_most_forcing(&mut moves);
+        assert_eq!(moves, vec![(7, 7)]);
+        let mut moves = vec![(1, 13), (13, 1)];
+        game.keep_most_forcing(&mut moves);
+        assert_eq!(moves, vec![(1, 13), (13, 1)]);
+    }
+
+    #[test]
+    fn binds_where_asked() {
+        assert_eq!(bind_address(None, 54321), Some("0.0.0.0:54321".parse().unwrap()));
+        assert_eq!(bind_address(Some("::1"), 7000), Some("[::1]:7000".parse().unwrap()));
+        assert_eq!(bind_address(Some("localhost"), 7000), None);
+        let addr = bind_address(Some("127.0.0.1:0"), 54321).unwrap();
+        let listener = TcpListener::bind(addr).unwrap();
+        let bound = listener.local_addr().unwrap();
+        assert_eq!(bound.ip(), IpAddr::from([127, 0, 0, 1]));
+        assert_ne!(bound.port(), 0);
+    }
+
+    #[test]
+    fn overline_only_wins_when_allowed() {
+        let mut game = game(15);
+        place(&mut game, &[(2, 4), (3, 4), (4, 4), (6, 4), (7, 4)], Player::Me);
+        place(&mut game, &[(2, 8), (3, 8), (4, 8), (5, 8)], Player::Me);
+        assert!(game.completes_five(5, 4, Player::Me));
+        game.allow_overline = false;
+        assert!(!game.completes_five(5, 4, Player::Me));
+        assert!(game.completes_five(6, 8, Player::Me) && game.completes_five(1, 8, Player::Me));
+        game.place_stone(5, 4, Player::Me);
+        assert!(!game.completes_five(5, 4, Player::Me));
+    }
+
+    #[test]
+    fn protocol_version_is_checked() {
+        let mut game = game(15);
+        let reply = send(&mut game, r#"{"command":"start","version":2}"#);
+        assert_eq!(reply["error"]["code"], "UNSUPPORTED_VERSION");
+        assert!(game.history.is_empty());
+        assert_eq!(send(&mut game, r#"{"command":"remaining","version":1}"#)["empty"], 225);
+        assert_eq!(send(&mut game, r#"{"command":"remaining"}"#)["version"], 1);
+
+        let server = Server::new(GameState::new(15, 15));
+        let (reply, _) = process_line(&server, None, r#"{"command":"ping","version":2}"#);
+        assert!(reply.contains("UNSUPPORTED_VERSION"), "{}", reply);
+        let (reply, _) = process_line(&server, None, r#"{"command":"ping","version":1}"#);
+        assert!(reply.contains("pong"), "{}", reply);
+    }
+
+    #[test]
+    fn renju_forbids_black_double_three() {
+        let mut game = game(15);
+        game.renju = true;
+        place(&mut game, &[(5, 7), (6, 7), (7, 5), (7, 6)], Player::Me);
+        place(&mut game, &[(1, 1), (13, 1), (1, 13), (13, 13)], Player::Opponent);
+        assert!(game.is_forbidden(7, 7, Player::Me));
+        assert!(game.move_legality(7, 7, Player::Me).is_err());
+        assert_eq!(send(&mut game, r#"{"command":"force","move":{"x":7,"y":7}}"#)["error"]["code"], "FORBIDDEN");
+        assert_ne!(game.find_best_move(), Some((7, 7)));
+
+        // After switching sides those stones are the opponent's, and black is theirs to play.
+        send(&mut game, r#"{"command":"set_color","color":"white"}"#);
+        assert!(!game.is_forbidden(7, 7, Player::Me));
+        let reply = send(&mut game, r#"{"command":"is_legal","move":{"x":7,"y":7}}"#);
+        assert_eq!(reply["legal"], false);
+        assert_eq!(reply["reason"], "Forbidden by renju rules");
+    }
+
+    #[test]
+    fn aspiration_window_searches_fewer_nodes() {
+        let mut game = game(15);
+        place(&mut game, &[(7, 7), (8, 8), (6, 8), (9, 6)], Player::Me);
+        place(&mut game, &[(8, 7), (7, 8), (6, 6), (10, 5)], Player::Opponent);
+        // As iterative deepening would: depth 1 gives the expected score for depth 3.
+        let expected = game.search_root(1, (-INF, INF)).unwrap().best_score;
+        game.search_root(2, (-INF, INF));
+        let (mut full, mut narrow) = (game.clone(), game.clone());
+        full.nodes = 0;
+        narrow.nodes = 0;
+        let wide = full.search_root(3, (-INF, INF)).unwrap();
+        let window = (expected - ASPIRATION_WINDOW, expected + ASPIRATION_WINDOW);
+        let aspired = narrow.search_root(3, window).unwrap();
+        assert!(aspired.best_score > window.0 && aspired.best_score < window.1);
+        assert_eq!((aspired.best_score, aspired.best_moves), (wide.best_score, wide.best_moves));
+        assert!(narrow.nodes < full.nodes, "{} nodes in the window, {} without", narrow.nodes, full.nodes);
+    }
+
+    #[test]
+    fn threats_follow_the_stones_after_set_color() {
+        let mut game = game(15);
+        place(&mut game, &[(5, 7), (6, 7), (7, 7)], Player::Me);
+        place(&mut game, &[(3, 3), (3, 4), (3, 5), (3, 6)], Player::Opponent);
+        let before = game.list_threats();
+        send(&mut game, r#"{"command":"set_color","color":"white"}"#);
+        let after = game.list_threats();
+        assert_eq!(before.len(), 2);
+        assert_eq!(after.len(), 2);
+        for (old, new) in before.iter().zip(&after) {
+            assert_eq!((new.player, new.threat, &new.cells), (old.player.other(), old.threat, &old.cells));
+        }
+        assert!(after.iter().any(|t| t.player == Player::Opponent && t.threat == LineThreat::OpenThree));
+        assert!(after.iter().any(|t| t.player == Player::Me && t.threat == LineThreat::OpenFour));
+    }
+
+    #[test]
+    fn connection_over_the_limit_is_refused() {
+        let mut server = Server::new(game(15));
+        server.max_connections = Some(2);
+        let running = Running::start(server);
+        let mut first = running.connect();
+        let mut second = running.connect();
+        assert_eq!(first.ask(r#"{"command":"ping"}"#)["reply"], "pong");
+        assert_eq!(second.ask(r#"{"command":"ping"}"#)["reply"], "pong");
+        let mut third = running.connect();
+        let refused = third.reply().unwrap();
+        assert_eq!(refused["error"]["message"], "Too many connections");
+        assert!(third.reply().is_none());
+        assert_eq!(first.ask(r#"{"command":"ping"}"#)["reply"], "pong");
+    }
+
+    #[test]
+    fn book_reply_matches_rotations() {
+        let book: Book = serde_json::from_str(r#"[{"moves":[[7,7],[9,8]],"reply":[5,5]}]"#).unwrap();
+        let book = Arc::new(book);
+
+        let mut game = game(15);
+        game.book = Arc::clone(&book);
+        place(&mut game, &[(7, 7)], Player::Me);
+        place(&mut game, &[(9, 8)], Player::Opponent);
+        let (cell, info) = game.find_best_move_with_info();
+        assert_eq!((cell, info.reason), (Some((5, 5)), "book"));
+
+        // The same opening turned a quarter clockwise: (x, y) goes to (14 - y, x).
+        let mut game = GameState::new(15, 15);
+        game.book = book;
+        place(&mut game, &[(7, 7)], Player::Me);
+        place(&mut game, &[(6, 9)], Player::Opponent);
+        let (cell, info) = game.find_best_move_with_info();
+        assert_eq!((cell, info.reason), (Some((9, 5)), "book"));
+
+        place(&mut game, &[(9, 5)], Player::Me);
+        place(&mut game, &[(3, 3)], Player::Opponent);
+        assert_eq!(game.book_move(), None);
+    }
+
+    #[test]
+    fn quit_closes_the_connection() {
+        let running = Running::start(Server::new(game(15)));
+        let mut client = running.connect();
+        assert_eq!(client.ask(r#"{"command":"quit"}"#)["reply"], "bye");
+        assert!(client.reply().is_none());
+        // Whatever the client sends now goes nowhere; the server has stopped reading.
+        let _ = writeln!(client.stream, r#"{{"command":"ping"}}"#);
+        assert!(client.reply().is_none());
+    }
+
+    #[test]
+    fn defensive_style_blocks_where_aggressive_builds() {
+        let play = |style: &str| {
+            let mut game = game(15);
+            game.search_depth = 2;
+            game.weights = Weights::for_style(style).unwrap();
+            place(&mut game, &[(8, 4), (7, 7), (8, 6)], Player::Me);
+            place(&mut game, &[(6, 9), (9, 8), (6, 10)], Player::Opponent);
+            game.find_best_move()
+        };
+        // Aggressive makes a three out of its own column; defensive caps their two on column 6.
+        assert_eq!(play("aggressive"), Some((8, 7)));
+        assert_eq!(play("defensive"), Some((6, 8)));
+    }
+
+    #[test]
+    fn is_legal_checks_bounds_and_occupancy() {
+        let mut game = game(15);
+        place(&mut game, &[(7, 7)], Player::Me);
+        let reply = send(&mut game, r#"{"command":"is_legal","move":{"x":15,"y":3}}"#);
+        assert_eq!((&reply["legal"], &reply["reason"]), (&false.into(), &"Coordinate out of bounds".into()));
+        let reply = send(&mut game, r#"{"command":"is_legal","move":{"x":7,"y":7}}"#);
+        assert_eq!((&reply["legal"], &reply["reason"]), (&false.into(), &"Cell is occupied".into()));
+        let reply = send(&mut game, r#"{"command":"is_legal","move":{"x":8,"y":7}}"#);
+        assert_eq!((&reply["legal"], &reply["reason"]), (&true.into(), &serde_json::Value::Null));
+        assert_eq!(game.history.len(), 1);
+    }
+
+    #[test]
+    fn center_bias_only_breaks_ties() {
+        let mut game = game(31);
+        game.center_bias = 10_000;
+        place(&mut game, &[(2, 2), (3, 2)], Player::Me);
+        // Far from the center, the end of a two still beats an empty cell in the middle.
+        assert!(game.score_cell(4, 2) > game.score_cell(15, 15));
+        // Between two cells that make nothing, the nearer one to the center wins.
+        game.center_bias = 1;
+        assert!(game.score_cell(15, 15) > game.score_cell(16, 15));
+        assert!(game.score_cell(16, 15) > game.score_cell(20, 20));
+        game.center_bias = 0;
+        assert_eq!(game.score_cell(15, 15), game.score_cell(20, 20));
+    }
+
+    #[test]
+    fn match_counts_two_games() {
+        let mut game = game(15);
+        send(&mut game, r#"{"command":"new_match","n":2}"#);
+
+        send(&mut game, r#"{"command":"start"}"#);
+        place(&mut game, &[(8, 7), (9, 7), (10, 7)], Player::Me);
+        place(&mut game, &[(0, 0), (0, 1), (0, 2)], Player::Opponent);
+        let won = send(&mut game, r#"{"command":"move","opponent_move":{"x":0,"y":3}}"#);
+        assert_eq!(won["winner"], "team crabs");
+        let stones = game.history.len();
+        // The finished game stays put, so a resent move gets the same winning answer.
+        assert_eq!(send(&mut game, r#"{"command":"move","opponent_move":{"x":0,"y":3}}"#), won);
+        assert_eq!(game.history.len(), stones);
+        let status = send(&mut game, r#"{"command":"match_status"}"#);
+        assert_eq!((status["played"].as_u64(), status["wins"].as_u64()), (Some(1), Some(1)));
+
+        assert_eq!(send(&mut game, r#"{"command":"start"}"#)["move"], serde_json::json!({"x": 7, "y": 7}));
+        assert_eq!(game.history.len(), 1);
+        place(&mut game, &[(0, 0), (0, 1), (0, 2), (0, 3)], Player::Opponent);
+        place(&mut game, &[(3, 3), (5, 5), (9, 9)], Player::Me);
+        let lost = send(&mut game, r#"{"command":"move","opponent_move":{"x":0,"y":4}}"#);
+        assert_eq!(lost["winner"], "opponent");
+
+        let status = send(&mut game, r#"{"command":"match_status"}"#);
+        assert_eq!(
+            status,
+            serde_json::json!({"version": 1, "games": 2, "played": 2, "wins": 1, "losses": 1, "draws": 0})
+        );
+    }
+
+    #[test]
+    fn line_table_agrees_with_classify_line() {
+        for code in 0..3usize.pow(9) {
+            let line: Vec<char> = (0..9).map(|i| ['.', 'O', 'B'][code / 3usize.pow(i) % 3]).collect();
+            assert_eq!(line_threat(code), GameState::classify_line(&line, Player::Opponent), "{:?}", line);
+        }
+
+        let mut game = game(15);
+        let mut rng = StdRng::seed_from_u64(61);
+        for i in 0..80 {
+            let (x, y) = (rng.gen_range(0..15), rng.gen_range(0..15));
+            if game.is_empty(x, y) {
+                place(&mut game, &[(x, y)], if i % 2 == 0 { Player::Me } else { Player::Opponent });
+            }
+        }
+        for (x, y) in (0..15).flat_map(|y| (0..15).map(move |x| (x, y))) {
+            for &(dx, dy) in &DIRECTIONS {
+                for player in [Player::Me, Player::Opponent] {
+                    let window = game.line_window(x, y, dx, dy, player);
+                    let read = game.evaluate_line_type(x, y, dx, dy, player);
+                    assert_eq!(read, GameState::classify_line(&window, player));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn batch_replies_in_order() {
+        let server = Server::new(game(15));
+        let input = [
+            r#"{"command":"start"}"#,
+            "",
+            r#"{"command":"move","opponent_move":{"x":8,"y":8}}"#,
+            r#"{"command":"reset"}"#,
+        ]
+        .join("\n");
+        let mut out = vec![];
+        run_batch(&server, input.as_bytes(), &mut out).unwrap();
+        let replies: Vec<serde_json::Value> =
+            String::from_utf8(out).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["move"], serde_json::json!({"x": 7, "y": 7}));
+        assert_eq!(replies[1]["team"], "team crabs");
+        assert!(replies[1]["move"].is_object());
+        assert_eq!(replies[2]["reply"], "ok");
+        assert!(lock(&server.session("")).history.is_empty());
+    }
+
+    #[test]
+    fn threats_read_each_direction() {
+        let mut game = game(15);
+        place(&mut game, &[(5, 7), (6, 7), (7, 7)], Player::Me);
+        let reply = send(&mut game, r#"{"command":"threats","move":{"x":8,"y":7}}"#);
+        let expected = serde_json::json!([
+            {"dx": 1, "dy": 0, "mine": "open_three", "opponent": "other"},
+            {"dx": 0, "dy": 1, "mine": "other", "opponent": "other"},
+            {"dx": 1, "dy": 1, "mine": "other", "opponent": "other"},
+            {"dx": 1, "dy": -1, "mine": "other", "opponent": "other"},
+        ]);
+        assert_eq!(reply["directions"], expected);
+    }
+
+    #[test]
+    fn newline_frames_span_reads_and_cap_the_size() {
+        // A request split across two reads, with the next one arriving in the second.
+        let first: &[u8] = br#"{"command":"pi"#;
+        let second: &[u8] = b"ng\"}\n{\"command\":\"quit\"}\n";
+        let mut reader = BufReader::with_capacity(4, first.chain(second));
+        let Frame::Request(line) = Framing::Newline.read(&mut reader).unwrap() else { panic!("no request") };
+        assert_eq!(line, "{\"command\":\"ping\"}\n");
+        let Frame::Request(line) = Framing::Newline.read(&mut reader).unwrap() else { panic!("no request") };
+        assert_eq!(line, "{\"command\":\"quit\"}\n");
+        assert!(matches!(Framing::Newline.read(&mut reader).unwrap(), Frame::Closed));
+
+        // The cap falls inside a two-byte character; the line is still just too large.
+        let mut long = "é".repeat(MAX_MESSAGE_BYTES / 2 + 1);
+        long.push('\n');
+        long.push_str("{\"command\":\"ping\"}\n");
+        let mut reader = BufReader::new(long.as_bytes());
+        assert!(matches!(Framing::Newline.read(&mut reader).unwrap(), Frame::TooLarge));
+        let Frame::Request(line) = Framing::Newline.read(&mut reader).unwrap() else { panic!("no request") };
+        assert_eq!(line, "{\"command\":\"ping\"}\n");
+    }
+
+    #[test]
+    fn second_move_stays_next_to_the_first() {
+        for opponent in [(8, 8), (7, 6), (10, 4), (3, 12)] {
+            let mut game = game(15);
+            send(&mut game, r#"{"command":"start"}"#);
+            let cmd = format!(r#"{{"command":"move","opponent_move":{{"x":{},"y":{}}}}}"#, opponent.0, opponent.1);
+            let reply = send(&mut game, &cmd);
+            let (x, y) = (reply["move"]["x"].as_u64().unwrap(), reply["move"]["y"].as_u64().unwrap());
+            assert!(x.abs_diff(7) <= 2 && y.abs_diff(7) <= 2, "({}, {}) after {:?}", x, y, opponent);
+        }
+    }
+
+    #[test]
+    fn extensions_find_a_win_beyond_the_depth() {
+        let mine = [(5, 7), (6, 4), (6, 9), (7, 10), (5, 10), (5, 6), (8, 9), (9, 10)];
+        let theirs = [(4, 5), (4, 7), (7, 5), (6, 6), (9, 4), (9, 9), (6, 7), (4, 9)];
+        let mut game = game(15);
+        for (&mine, &theirs) in mine.iter().zip(&theirs) {
+            place(&mut game, &[mine], Player::Me);
+            place(&mut game, &[theirs], Player::Opponent);
+        }
+        // A run of fours that only ends in five well past two plies.
+        assert!(game.vcf_search(Player::Me, VCF_DEPTH).is_some_and(|line| line.len() > 3));
+        let mut plain = game.clone();
+        // As if the line had already used up its extensions.
+        plain.extensions = MAX_EXTENSIONS;
+        let plain = plain.search_root(2, (-INF, INF)).unwrap();
+        let extended = game.search_root(2, (-INF, INF)).unwrap();
+        assert!(plain.best_score < WIN_SCORE, "{}", plain.best_score);
+        assert!(extended.best_score >= WIN_SCORE, "{}", extended.best_score);
+        assert_ne!(plain.best_moves, extended.best_moves);
+    }
+
+    #[test]
+    fn ping_mid_game_leaves_the_board_alone() {
+        let server = Server::new(game(15));
+        process_line(&server, None, r#"{"command":"start"}"#);
+        process_line(&server, None, r#"{"command":"move","opponent_move":{"x":8,"y":8}}"#);
+        let before = lock(&server.session("")).history.clone();
+        assert_eq!(before.len(), 3);
+        // Answered without the game's lock, even while something else holds it.
+        let state = server.session("");
+        let held = lock(&state);
+        let (reply, quit) = process_line(&server, None, r#"{"command":"ping"}"#);
+        drop(held);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&reply).unwrap()["reply"], "pong");
+        assert!(!quit);
+        assert_eq!(lock(&server.session("")).history, before);
+    }
+
+    #[test]
+    fn render_draws_the_stones() {
+        let mut small = game(5);
+        place(&mut small, &[(1, 0)], Player::Me);
+        place(&mut small, &[(3, 2)], Player::Opponent);
+        let expected = [
+            "     0  1  2  3  4",
+            "  0  .  X  .  .  .",
+            "  1  .  .  .  .  .",
+            "  2  .  .  .  O  .",
+            "  3  .  .  .  .  .",
+            "  4  .  .  .  .  .",
+        ];
+        assert_eq!(small.render(), expected.join("\n") + "\n");
+
+        // Past MAX_RENDER only the stones and two cells around them are drawn.
+        let mut large = game(31);
+        place(&mut large, &[(10, 10)], Player::Me);
+        place(&mut large, &[(11, 12)], Player::Opponent);
+        let rendered = large.render();
+        let rows: Vec<&str> = rendered.lines().collect();
+        assert_eq!(rows[0], "     8  9 10 11 12 13");
+        assert_eq!(rows[3], " 10  .  .  X  .  .  .");
+        assert_eq!(rows[5], " 12  .  .  .  O  .  .");
+        assert_eq!(rows.len(), 8);
+    }
+
+    #[test]
+    fn largest_weights_do_not_overflow() {
+        let most = ThreatWeights {
+            five: i32::MAX,
+            open_four: i32::MAX,
+            gap_four: i32::MAX,
+            blocked_four: i32::MAX,
+            split: i32::MAX,
+            open_three: i32::MAX,
+            broken_three: i32::MAX,
+            blocked_three: i32::MAX,
+            two: i32::MAX,
+            four_three: i32::MAX,
+            double_three: i32::MAX,
+            double_four: i32::MAX,
+        };
+        let mut game = game(31);
+        game.weights = Weights { mine: most.clone(), opponent: most };
+        // Every line through (14, 10) is a four, two of ours and two of theirs.
+        place(&mut game, &[(10, 10), (11, 10), (12, 10), (13, 10), (14, 6), (14, 7), (14, 8), (14, 9)], Player::Me);
+        let theirs = [(10, 6), (11, 7), (12, 8), (13, 9), (15, 9), (16, 8), (17, 7), (18, 6)];
+        place(&mut game, &theirs, Player::Opponent);
+        assert_eq!(game.score_cell(14, 10), INF);
+        assert!(game.static_score().abs() <= INF);
+        assert!(game.evaluate_position().abs() <= INF);
+        assert!(game.search_root(2, (-INF, INF)).is_some());
+        let (x, y) = game.find_best_move().unwrap();
+        assert!(game.completes_five(x, y, Player::Me), "({}, {})", x, y);
+    }
+
+    #[test]
+    fn remaining_counts_down_with_each_stone() {
+        let mut game = game(31);
+        send(&mut game, r#"{"command":"start"}"#);
+        send(&mut game, r#"{"command":"move","opponent_move":{"x":3,"y":3}}"#);
+        let reply = send(&mut game, r#"{"command":"remaining"}"#);
+        assert_eq!((reply["empty"].as_u64(), reply["total"].as_u64()), (Some(961 - 3), Some(961)));
+        send(&mut game, r#"{"command":"undo"}"#);
+        assert_eq!(send(&mut game, r#"{"command":"remaining"}"#)["empty"], 961 - 2);
+    }
+
+    #[test]
+    fn error_level_keeps_everything_else_quiet() {
+        let play = |level: LogLevel| {
+            TEST_LOG_LEVEL.set(Some(level));
+            TEST_LOG.take();
+            let mut game = game(15);
+            game.verbose = log_enabled(LogLevel::Debug);
+            send(&mut game, r#"{"command":"start"}"#);
+            let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":3,"y":4}}"#);
+            assert!(reply["move"].is_object(), "{}", reply);
+            TEST_LOG.take()
+        };
+        assert_eq!(play(LogLevel::Error), Vec::<String>::new());
+        // The same game at debug level logs the decision, so the quiet one isn't quiet by accident.
+        assert_eq!(play(LogLevel::Debug).len(), 1);
+    }
+
+    #[test]
+    fn blocks_a_four_rather_than_attack() {
+        let mut game = game(15);
+        // Our open three would become an open four, but their four finishes first.
+        place(&mut game, &[(5, 9), (6, 9), (7, 9), (2, 3)], Player::Me);
+        place(&mut game, &[(3, 3), (4, 3), (5, 3), (6, 3)], Player::Opponent);
+        let (cell, info) = game.find_best_move_with_info();
+        assert_eq!((cell, info.reason), (Some((7, 3)), "block"));
+
+        // Unless we have a five of our own to play.
+        place(&mut game, &[(8, 9)], Player::Me);
+        let (x, y) = game.find_best_move().unwrap();
+        assert!(game.completes_five(x, y, Player::Me));
+    }
+
+    #[test]
+    fn two_workers_serve_four_connections() {
+        let mut server = Server::new(game(15));
+        server.workers = Some(2);
+        let running = Running::start(server);
+        let clients: Vec<Client> = (0..4).map(|_| running.connect()).collect();
+        // Two wait in the queue until a worker is done with an earlier connection.
+        let handles: Vec<_> = clients
+            .into_iter()
+            .map(|mut client| {
+                thread::spawn(move || {
+                    let pong = client.ask(r#"{"command":"ping"}"#);
+                    let bye = client.ask(r#"{"command":"quit"}"#);
+                    (pong["reply"].clone(), bye["reply"].clone())
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), ("pong".into(), "bye".into()));
+        }
+    }
+
+    #[test]
+    fn deterministic_games_repeat_whatever_the_seed() {
+        let fresh = |seed| {
+            let mut game = GameState::new(15, 15);
+            game.search_depth = 2;
+            game.deterministic = true;
+            game.rng = StdRng::seed_from_u64(seed);
+            game
+        };
+        let (mut a, mut b) = (fresh(1), fresh(2));
+        send(&mut a, r#"{"command":"start"}"#);
+        send(&mut b, r#"{"command":"start"}"#);
+        for (x, y) in [(8, 8), (1, 13), (13, 13), (2, 7), (12, 2)] {
+            if !a.is_empty(x, y) {
+                continue;
+            }
+            let line = format!(r#"{{"command":"move","opponent_move":{{"x":{},"y":{}}}}}"#, x, y);
+            assert_eq!(send(&mut a, &line), send(&mut b, &line));
+        }
+        assert_eq!(a.history, b.history);
+        // Of tied cells, the one first in reading order.
+        assert_eq!(a.pick(&[(9, 4), (3, 6), (2, 4)]), Some((2, 4)));
+    }
+
+    #[test]
+    fn pente_captures_along_every_line() {
+        for &(dx, dy) in &DIRECTIONS {
+            let cell = |k: isize| (7usize.wrapping_add_signed(k * dx), 7usize.wrapping_add_signed(k * dy));
+            let mut game = game(15);
+            game.pente = true;
+            place(&mut game, &[cell(0)], Player::Me);
+            place(&mut game, &[cell(1), cell(2)], Player::Opponent);
+            place(&mut game, &[cell(3)], Player::Me);
+            let mut captured = game.capture(cell(3).0, cell(3).1, Player::Me);
+            captured.sort();
+            let mut pair = vec![cell(1), cell(2)];
+            pair.sort();
+            assert_eq!(captured, pair, "direction ({}, {})", dx, dy);
+            assert!(pair.iter().all(|&(x, y)| game.is_empty(x, y)));
+            assert_eq!(game.history.len(), 2);
+            assert_eq!(game.board_score, game.full_rescore());
+        }
+
+        let mut game = game(15);
+        place(&mut game, &[(7, 7), (10, 7)], Player::Me);
+        place(&mut game, &[(8, 7), (9, 7)], Player::Opponent);
+        assert!(game.capture(10, 7, Player::Me).is_empty());
+        assert_eq!(game.history.len(), 4);
+    }
+
+    #[test]
+    fn refute_answers_without_playing() {
+        let mut game = game(15);
+        place(&mut game, &[(2, 3), (7, 7)], Player::Me);
+        place(&mut game, &[(3, 3), (4, 3), (5, 3)], Player::Opponent);
+        let (history, zobrist, score) = (game.history.clone(), game.zobrist, game.board_score);
+        // Their four at (6, 3) has just the one answer.
+        let reply = send(&mut game, r#"{"command":"refute","opponent_move":{"x":6,"y":3}}"#);
+        assert_eq!(reply["refutation"], serde_json::json!({"x": 7, "y": 3}));
+        assert_eq!((game.history, game.zobrist, game.board_score), (history, zobrist, score));
+    }
+
+    #[test]
+    fn rotated_positions_share_a_canonical_hash() {
+        let mine = [(7, 7), (8, 9), (3, 2)];
+        let theirs = [(8, 7), (12, 1), (5, 10)];
+        let rotate = |cells: &[(usize, usize)]| cells.iter().map(|&(x, y)| (14 - y, x)).collect::<Vec<_>>();
+        let mut game = game(15);
+        place(&mut game, &mine, Player::Me);
+        place(&mut game, &theirs, Player::Opponent);
+        let mut turned = GameState::new(15, 15);
+        place(&mut turned, &rotate(&mine), Player::Me);
+        place(&mut turned, &rotate(&theirs), Player::Opponent);
+        assert_ne!(game.zobrist, turned.zobrist);
+        assert_eq!(game.canonical_hash(), turned.canonical_hash());
+
+        // Swapping whose stones they are is a different position.
+        let mut swapped = GameState::new(15, 15);
+        place(&mut swapped, &mine, Player::Opponent);
+        place(&mut swapped, &theirs, Player::Me);
+        assert_ne!(game.canonical_hash(), swapped.canonical_hash());
+    }
+
+    #[test]
+    fn quiescence_sees_a_four_through_the_horizon() {
+        let mut game = game(15);
+        place(&mut game, &[(2, 7), (6, 3), (10, 10)], Player::Opponent);
+        place(&mut game, &[(3, 7), (4, 7), (5, 7), (6, 4), (6, 5), (6, 6)], Player::Me);
+        // Our move: (6, 7) makes two fours. The static score doesn't know that yet.
+        let fixed_depth = game.static_score();
+        assert!(fixed_depth < WIN_SCORE / 10, "{}", fixed_depth);
+        assert_eq!(game.minimax(0, -INF, INF, true), WIN_SCORE);
+        assert_eq!(game.quiescence(-INF, INF, true, QUIESCENCE_DEPTH), WIN_SCORE);
+        // Given no plies to look at fours with, it falls back on the static score.
+        assert_eq!(game.quiescence(-INF, INF, true, 0), fixed_depth);
+    }
+
+    #[test]
+    fn threat_detection_is_the_same_for_both_sides() {
+        let mut rng = StdRng::seed_from_u64(79);
+        let (mut game, mut swapped) = (game(15), GameState::new(15, 15));
+        for i in 0..70 {
+            let (x, y) = (rng.gen_range(0..15), rng.gen_range(0..15));
+            let player = if i % 2 == 0 { Player::Me } else { Player::Opponent };
+            if game.is_empty(x, y) {
+                place(&mut game, &[(x, y)], player);
+                place(&mut swapped, &[(x, y)], player.other());
+            }
+        }
+        for (x, y) in (0..15).flat_map(|y| (0..15).map(move |x| (x, y))) {
+            for &(dx, dy) in &DIRECTIONS {
+                for player in [Player::Me, Player::Opponent] {
+                    let threat = game.evaluate_line_type(x, y, dx, dy, player);
+                    assert_eq!(threat, swapped.evaluate_line_type(x, y, dx, dy, player.other()));
+                }
+            }
+        }
+        let (threats, swapped_threats) = (game.list_threats(), swapped.list_threats());
+        assert_eq!(threats.len(), swapped_threats.len());
+        for (threat, swapped) in threats.iter().zip(&swapped_threats) {
+            assert_eq!((threat.player, threat.threat), (swapped.player.other(), swapped.threat));
+        }
+        assert_eq!(game.winning_move(Player::Me), swapped.winning_move(Player::Opponent));
+    }
+
+    #[test]
+    fn rate_limit_stops_a_burst_but_not_a_slow_client() {
+        let mut server = Server::new(game(15));
+        server.rate = Some(3);
+        let (bursty, slow): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let ping = |ip| {
+            let (reply, _) = process_line(&server, Some(ip), r#"{"command":"ping"}"#);
+            serde_json::from_str::<serde_json::Value>(&reply).unwrap()
+        };
+        let replies: Vec<serde_json::Value> = (0..6).map(|_| ping(bursty)).collect();
+        assert!(replies[..3].iter().all(|reply| reply["reply"] == "pong"));
+        assert!(replies[3..].iter().all(|reply| reply["error"]["message"] == "Rate limited"));
+        // Another address has its own allowance, and one command every so often stays under it.
+        for _ in 0..3 {
+            assert_eq!(ping(slow)["reply"], "pong");
+            thread::sleep(Duration::from_millis(400));
+        }
+        assert_eq!(ping(slow)["reply"], "pong");
+    }
+
+    #[test]
+    fn a_run_is_listed_once_from_any_stone() {
+        let mut game = game(15);
+        place(&mut game, &[(3, 7), (4, 7), (5, 7), (7, 7), (8, 7)], Player::Opponent);
+        place(&mut game, &[(10, 2)], Player::Me);
+        let threats = game.list_threats();
+        assert_eq!(threats.len(), 1, "{:?}", threats.iter().map(|t| &t.cells).collect::<Vec<_>>());
+        assert_eq!((threats[0].player, threats[0].threat), (Player::Opponent, LineThreat::GapFour));
+        let cells: Vec<(usize, usize)> = threats[0].cells.iter().map(|cell| (cell.x, cell.y)).collect();
+        assert_eq!(cells, [(3, 7), (4, 7), (5, 7), (7, 7), (8, 7)]);
+        for x in [3, 4, 5, 7, 8] {
+            assert_eq!(game.run_cells(x, 7, 1, 0, Player::Opponent), cells);
+        }
+    }
+
+    #[test]
+    fn poisoned_game_lock_is_recovered() {
+        let server = Server::new(game(15));
+        let state = server.session("");
+        let poisoner = Arc::clone(&state);
+        let panicked = thread::spawn(move || {
+            let _held = poisoner.lock().unwrap();
+            panic!("poisoning the game lock on purpose");
+        })
+        .join();
+        assert!(panicked.is_err() && state.is_poisoned());
+        let (reply, _) = process_line(&server, None, r#"{"command":"start"}"#);
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply["move"], serde_json::json!({"x": 7, "y": 7}));
+        assert!(!state.is_poisoned());
+        assert_eq!(lock(&state).history.len(), 1);
+    }
+
+    #[test]
+    fn timing_counts_every_decision() {
+        let mut game = game(15);
+        game.search_depth = 2;
+        send(&mut game, r#"{"command":"start"}"#);
+        for (x, y) in [(8, 8), (1, 13), (13, 1)] {
+            let line = format!(r#"{{"command":"move","opponent_move":{{"x":{},"y":{}}}}}"#, x, y);
+            send(&mut game, &line);
+        }
+        let total = |timing: &serde_json::Value| {
+            ["under_1ms", "under_10ms", "under_100ms", "under_1s", "over_1s"]
+                .iter()
+                .map(|bucket| timing[bucket].as_u64().unwrap())
+                .sum::<u64>()
+        };
+        assert_eq!(total(&send(&mut game, r#"{"command":"timing"}"#)), 3);
+        send(&mut game, r#"{"command":"reset"}"#);
+        assert_eq!(total(&send(&mut game, r#"{"command":"timing"}"#)), 0);
+    }
+
+    #[test]
+    fn opponent_move_aliases_parse_alike() {
+        for key in ["opponentMove", "opponent_move", "lastMove", "last_move"] {
+            let line = format!(r#"{{"command":"move","{}":{{"x":"4","y":9}}}}"#, key);
+            let cmd: Command = serde_json::from_str(&line).unwrap();
+            assert_eq!(cmd.opponent_move.map(|cell| cell.to_xy().unwrap()), Some((4, 9)), "{}", key);
+        }
+    }
+
+    #[test]
+    fn force_plays_our_stone() {
+        let mut game = game(15);
+        let reply = send(&mut game, r#"{"command":"force","move":{"x":3,"y":4}}"#);
+        assert_eq!(reply["move"], serde_json::json!({"x": 3, "y": 4}));
+        assert_eq!(game.stone_at(3, 4), Some(Player::Me));
+        assert!(!game.first_move);
+        let reply = send(&mut game, r#"{"command":"force","move":{"x":3,"y":4}}"#);
+        assert_eq!(reply["error"]["code"], "OCCUPIED");
+
+        // While a swap2 opening waits for its decision, no stone is ours yet.
+        let mut game = GameState::new(15, 15);
+        send(&mut game, r#"{"command":"swap2_propose"}"#);
+        let reply = send(&mut game, r#"{"command":"force","move":{"x":3,"y":4}}"#);
+        assert_eq!(reply["error"]["message"], "Swap2 decision pending");
+        assert!(game.history.is_empty());
+    }
+
+    #[test]
+    fn double_four_cell_is_chosen() {
+        let mut game = game(15);
+        place(&mut game, &[(3, 7), (4, 7), (5, 7), (6, 4), (6, 5), (6, 6)], Player::Me);
+        place(&mut game, &[(2, 7), (6, 3), (10, 10)], Player::Opponent);
+        // (6, 7) makes a four along the row and another down the column; (7, 7) or (6, 8) only one.
+        assert!(game.score_cell(6, 7) >= game.weights.mine.double_four);
+        assert!(game.score_cell(6, 7) > game.score_cell(7, 7).max(game.score_cell(6, 8)));
+        assert_eq!(game.find_best_move(), Some((6, 7)));
+    }
+
+    #[test]
+    fn weights_left_out_keep_their_sides_default() {
+        let weights: Weights = serde_json::from_str(r#"{"mine":{"five":5},"opponent":{"open_four":4}}"#).unwrap();
+        let defaults = Weights::default();
+        assert_eq!((weights.mine.five, weights.opponent.open_four), (5, 4));
+        assert_eq!(weights.mine.double_four, 800_000);
+        assert_eq!(weights.opponent.double_four, 700_000);
+        assert_eq!(weights.opponent.five, defaults.opponent.five);
+        let weights: Weights = serde_json::from_str("{}").unwrap();
+        assert_eq!(weights.opponent.two, defaults.opponent.two);
+    }
+
+    #[test]
+    fn length_frames_round_trip() {
+        let requests = [r#"{"command":"ping"}"#, "", r#"{"command":"move","opponent_move":{"x":"é","y":1}}"#];
+        let mut wire = vec![];
+        for request in requests {
+            Framing::Length.write(&mut wire, request).unwrap();
+        }
+        assert_eq!(&wire[..4], &[0, 0, 0, 18]);
+        let mut reader = BufReader::with_capacity(5, wire.as_slice());
+        for expected in requests {
+            let Frame::Request(request) = Framing::Length.read(&mut reader).unwrap() else { panic!("no request") };
+            assert_eq!(request, expected);
+        }
+        assert!(matches!(Framing::Length.read(&mut reader).unwrap(), Frame::Closed));
+
+        // A length over the limit is skipped along with its body.
+        let mut wire = (MAX_MESSAGE_BYTES as u32 + 1).to_be_bytes().to_vec();
+        wire.extend(vec![b' '; MAX_MESSAGE_BYTES + 1]);
+        Framing::Length.write(&mut wire, r#"{"command":"ping"}"#).unwrap();
+        let mut reader = BufReader::new(wire.as_slice());
+        assert!(matches!(Framing::Length.read(&mut reader).unwrap(), Frame::TooLarge));
+        let Frame::Request(request) = Framing::Length.read(&mut reader).unwrap() else { panic!("no request") };
+        assert_eq!(request, requests[0]);
+    }
+
+    #[test]
+    fn ties_go_to_the_cell_in_line_with_our_last_stone() {
+        let mut game = GameState::new(15, 15);
+        place(&mut game, &[(3, 3), (7, 7)], Player::Me);
+        place(&mut game, &[(8, 7)], Player::Opponent);
+        let tied = [(2, 12), (9, 9)];
+        let picks = |game: &mut GameState| {
+            (0..20)
+                .map(|seed| {
+                    game.rng = StdRng::seed_from_u64(seed);
+                    game.pick(&tied).unwrap()
+                })
+                .collect::<HashSet<_>>()
+        };
+        assert_eq!(picks(&mut game).len(), 2);
+        game.follow_plan = true;
+        assert_eq!(picks(&mut game), HashSet::from([(9, 9)]));
+    }
+
+    #[test]
+    fn self_play_ends_with_a_consistent_game() {
+        let mut game = game(7);
+        game.search_depth = 1;
+        let server = Server::new(game);
+        let (reply, _) = process_line(&server, None, r#"{"command":"selfplay"}"#);
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        let moves: Vec<(usize, usize)> = reply["moves"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|cell| (cell["x"].as_u64().unwrap() as usize, cell["y"].as_u64().unwrap() as usize))
+            .collect();
+        let mut replay = GameState::new(7, 7);
+        for (i, &(x, y)) in moves.iter().enumerate() {
+            assert!(replay.move_legality(x, y, Player::Me).is_ok(), "move {} at ({}, {})", i, x, y);
+            replay.place_stone(x, y, if i % 2 == 0 { Player::Me } else { Player::Opponent });
+        }
+        let black_last = moves.len() % 2 == 1;
+        let (&(x, y), last) = (moves.last().unwrap(), if black_last { Player::Me } else { Player::Opponent });
+        match reply["result"].as_str().unwrap() {
+            "draw" => assert!(replay.is_draw() && !replay.completes_five(x, y, last)),
+            "black" => assert!(black_last && replay.completes_five(x, y, last)),
+            "white" => assert!(!black_last && replay.completes_five(x, y, last)),
+            other => panic!("unexpected result {}", other),
+        }
+        // It played on a copy; the session's own board is untouched.
+        assert!(lock(&server.session("")).history.is_empty());
+    }
+
+    #[test]
+    fn idle_session_is_reclaimed() {
+        let mut server = Server::new(game(15));
+        server.session_ttl = Some(Duration::from_millis(200));
+        process_line(&server, None, r#"{"command":"start","session":"idle"}"#);
+        assert_eq!(lock(&server.session("idle")).history.len(), 1);
+        thread::sleep(Duration::from_millis(300));
+        // Any command sweeps it; coming back afterwards finds a fresh game.
+        process_line(&server, None, r#"{"command":"reset","session":"other"}"#);
+        assert!(!lock(&server.sessions).contains_key("idle"));
+        assert!(lock(&server.session("idle")).history.is_empty());
+    }
+
+    #[test]
+    fn each_error_path_has_its_code() {
+        let mut game = game(15);
+        send(&mut game, r#"{"command":"start"}"#);
+        let cases = [
+            (r#"{"command":"ping","version":2}"#, "UNSUPPORTED_VERSION"),
+            (r#"{"command":"fly"}"#, "UNKNOWN_COMMAND"),
+            (r#"{"command":"move"}"#, "MISSING_FIELD"),
+            (r#"{"command":"move","opponent_move":{"x":"a","y":1}}"#, "INVALID_COORDINATE"),
+            (r#"{"command":"move","opponent_move":{"x":20,"y":1}}"#, "OUT_OF_BOUNDS"),
+            (r#"{"command":"move","opponent_move":{"x":7,"y":7}}"#, "OCCUPIED"),
+            (r#"{"command":"force","move":{"x":7,"y":7}}"#, "OCCUPIED"),
+            (r#"{"command":"refute","opponent_move":{"x":7,"y":7}}"#, "OCCUPIED"),
+            (r#"{"command":"start"}"#, "NOT_FIRST_MOVE"),
+            (r#"{"command":"save"}"#, "MISSING_FIELD"),
+            (r#"{"command":"save","path":"game"}"#, "INVALID_PATH"),
+            (r#"{"command":"load","path":"game"}"#, "INVALID_PATH"),
+            (r#"{"command":"match_status"}"#, "NO_MATCH"),
+            (r#"{"command":"swap2_decide","choice":"black"}"#, "INVALID_SWAP2"),
+            (r#"{"command":"load_game","moves":[{"x":1,"y":1},{"x":1,"y":1}]}"#, "ILLEGAL_MOVE"),
+        ];
+        for (line, code) in cases {
+            assert_eq!(send(&mut game, line)["error"]["code"], code, "{}", line);
+        }
+
+        let mut game = GameState::new(15, 15);
+        send(&mut game, r#"{"command":"swap2_propose"}"#);
+        let reply = send(&mut game, r#"{"command":"move","opponent_move":{"x":1,"y":1}}"#);
+        assert_eq!(reply["error"]["code"], "SWAP2_PENDING");
+        let reply = send(&mut game, r#"{"command":"swap2_decide","choice":"sideways"}"#);
+        assert_eq!(reply["error"]["code"], "INVALID_SWAP2");
+        let mut game = GameState::new(60, 60);
+        assert_eq!(send(&mut game, r#"{"command":"export"}"#)["error"]["code"], "BOARD_TOO_LARGE");
+
+        let mut server = Server::new(GameState::new(15, 15));
+        server.rate = Some(1);
+        let ip = "10.0.0.3".parse().unwrap();
+        for (line, code) in [("not json", "INVALID_JSON"), ("{}", "RATE_LIMITED")] {
+            let reply: serde_json::Value = serde_json::from_str(&process_line(&server, Some(ip), line).0).unwrap();
+            assert_eq!(reply["error"]["code"], code, "{}", line);
+        }
+    }
+
+    #[test]
+    fn expansion_reaches_a_move_two_cells_out() {
+        let mut game = game(15);
+        game.search_depth = 1;
+        game.radius = 2;
+        // (7, 7) is two cells from every stone, and the end of both twos at once.
+        place(&mut game, &[(9, 7), (10, 7), (7, 9), (7, 10)], Player::Me);
+        place(&mut game, &[(12, 12)], Player::Opponent);
+        assert!(!game.canonical_candidates(1).contains(&(7, 7)));
+        let next_to_stones = game.canonical_candidates(1).iter().map(|&(x, y)| game.score_cell(x, y)).max().unwrap();
+        assert!(next_to_stones < 150 && game.score_cell(7, 7) >= 150);
+
+        // Good enough next to the stones: no need to look further out.
+        game.expand_below = Some(50);
+        assert!(!game.search_candidates().contains(&(7, 7)));
+        game.expand_below = Some(150);
+        assert_eq!(game.search_candidates()[0], (7, 7));
+        assert_eq!(game.find_best_move(), Some((7, 7)));
+    }
+
+    #[test]
+    fn reported_depth_reaches_the_configured_maximum() {
+        let mut game = game(15);
+        game.search_depth = 3;
+        game.time_budget = Duration::from_secs(60);
+        send(&mut game, r#"{"command":"start"}"#);
+        let quiet = send(&mut game, r#"{"command":"move","opponent_move":{"x":8,"y":8}}"#);
+        assert!(quiet.get("depth").is_none());
+        game.report_depth = true;
+        let reply = send(&mut game, r#"{"command":"move","opponent_move":{"x":3,"y":12}}"#);
+        assert_eq!(reply["depth"], 3);
+    }
+
+    #[test]
+    fn export_writes_the_moves_as_sgf() {
+        let mut game = game(15);
+        send(&mut game, r#"{"command":"start"}"#);
+        place(&mut game, &[(3, 4)], Player::Opponent);
+        place(&mut game, &[(8, 14)], Player::Me);
+        assert_eq!(send(&mut game, r#"{"command":"export"}"#)["sgf"], "(;FF[4]GM[4]SZ[15];B[hh];W[de];B[io])");
+        assert_eq!(GameState::new(20, 10).to_sgf().unwrap(), "(;FF[4]GM[4]SZ[20:10])");
+    }
+
+    #[test]
+    fn bounded_window_matches_the_checked_one() {
+        // The window as it was read before the offsets were bounded: every cell checked in turn.
+        fn checked_window(g: &GameState, x: usize, y: usize, dx: isize, dy: isize, player: Player) -> Vec<char> {
+            (-4..=4)
+                .map(|offset| {
+                    let (nx, ny) = (x as isize + offset * dx, y as isize + offset * dy);
+                    if nx < 0 || ny < 0 || nx >= g.width as isize || ny >= g.height as isize {
+                        return 'B';
+                    }
+                    match g.stone_at(nx as usize, ny as usize) {
+                        None => '.',
+                        Some(stone) if stone == player => player.stone(),
+                        Some(_) => 'B',
+                    }
+                })
+                .collect()
+        }
+
+        let mut rng = StdRng::seed_from_u64(95);
+        for (width, height) in [(15, 15), (9, 20), (20, 6)] {
+            let mut g = GameState::new(width, height);
+            for i in 0..width * height / 3 {
+                let (x, y) = (rng.gen_range(0..width), rng.gen_range(0..height));
+                if g.is_empty(x, y) {
+                    place(&mut g, &[(x, y)], if i % 2 == 0 { Player::Me } else { Player::Opponent });
+                }
+            }
+            for _ in 0..500 {
+                let (x, y) = (rng.gen_range(0..width), rng.gen_range(0..height));
+                let player = if rng.gen_bool(0.5) { Player::Me } else { Player::Opponent };
+                for &(dx, dy) in &DIRECTIONS {
+                    let old = checked_window(&g, x, y, dx, dy, player);
+                    assert_eq!(g.line_window(x, y, dx, dy, player), old, "({}, {}) along ({}, {})", x, y, dx, dy);
+                    assert_eq!(g.evaluate_line_type(x, y, dx, dy, player), GameState::classify_line(&old, player));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mirror_replies_through_the_center() {
+        let mut game = game(15);
+        game.strategy = Strategy::Mirror;
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":3,"y":4}}"#);
+        assert_eq!(reply["move"], serde_json::json!({"x": 11, "y": 10}));
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":5,"y":12}}"#);
+        assert_eq!(reply["move"], serde_json::json!({"x": 9, "y": 2}));
+
+        // The center is its own reflection, so a stone there leaves the choice to the heuristic.
+        let reply = send(&mut game, r#"{"command":"move","opponentMove":{"x":7,"y":7}}"#);
+        assert!(reply["move"].is_object(), "{}", reply);
+        assert_eq!(game.history.len(), 6);
+    }
+
+    #[test]
+    fn forced_start_clears_the_game_and_recenters() {
+        let mut game = game(15);
+        send(&mut game, r#"{"command":"start"}"#);
+        send(&mut game, r#"{"command":"move","opponentMove":{"x":3,"y":4}}"#);
+        let reply = send(&mut game, r#"{"command":"start"}"#);
+        assert_eq!(reply["error"]["code"], "NOT_FIRST_MOVE");
+        assert_eq!(game.history.len(), 3);
+
+        let reply = send(&mut game, r#"{"command":"start","force":true}"#);
+        assert_eq!(reply["move"], serde_json::json!({"x": 7, "y": 7}));
+        assert_eq!(game.history.len(), 1);
+        assert_eq!(game.stone_at(7, 7), Some(Player::Me));
+        assert_eq!(game.stone_at(3, 4), None);
+        assert_eq!(game.my_color, Color::Black);
+    }
+
+    #[test]
+    fn broken_three_outscores_a_blocked_three() {
+        let window = |line: &str| line.chars().collect::<Vec<_>>();
+        let broken = GameState::classify_line(&window("BBXX.X.BB"), Player::Me);
+        let blocked = GameState::classify_line(&window("BBXXX..B."), Player::Me);
+        assert_eq!((broken, blocked), (LineThreat::BrokenThree, LineThreat::BlockedThree));
+        let weights = Weights::default();
+        for side in [&weights.mine, &weights.opponent] {
+            assert!(side.value(broken) > side.value(blocked));
+            assert!(side.value(broken) < side.value(LineThreat::OpenThree));
+        }
+
+        // A weight file from before broken threes keeps each side's own default for them.
+        let weights: Weights = serde_json::from_str(r#"{"mine":{"two":3},"opponent":{"two":4}}"#).unwrap();
+        assert_eq!((weights.mine.broken_three, weights.opponent.broken_three), (1_500, 2_000));
+    }
+
+    #[test]
+    fn eval_counts_each_run_once() {
+        let mut three = game(15);
+        place(&mut three, &[(5, 7), (6, 7), (7, 7)], Player::Me);
+        assert_eq!(three.evaluate_position(), three.weights.mine.open_three);
+        assert_eq!(send(&mut three, r#"{"command":"eval"}"#)["score"], three.weights.mine.open_three);
+
+        let mut four = three.clone();
+        place(&mut four, &[(8, 7)], Player::Me);
+        assert_eq!(four.evaluate_position(), four.weights.mine.open_four);
+        assert!(four.evaluate_position() > three.evaluate_position());
+
+        // The opponent's runs count against us at their own weights.
+        place(&mut three, &[(5, 10), (6, 10)], Player::Opponent);
+        let weights = &three.weights;
+        assert_eq!(three.evaluate_position(), weights.mine.open_three - weights.opponent.two);
+    }
+
+    #[test]
+    fn ignored_threes_shift_us_towards_building_them() {
+        let mut neutral = game(15);
+        neutral.search_depth = 2;
+        place(&mut neutral, &[(7, 7), (5, 6), (7, 8), (9, 6)], Player::Me);
+        place(&mut neutral, &[(5, 5), (6, 8), (8, 5)], Player::Opponent);
+        let mut exploiting = neutral.clone();
+        let mut seen = OpponentModel::default();
+        for _ in 0..4 {
+            seen.observe(1, 1);
+        }
+        exploiting.set_opponent_model(seen);
+        assert_eq!(neutral.find_best_move(), Some((8, 7)));
+        // (7, 6) opens a three down column 7, which this opponent has been letting stand.
+        assert_eq!(exploiting.find_best_move(), Some((7, 6)));
+
+        // What was learned lasts into the match's next game and through a forced restart, but not
+        // past a reset.
+        exploiting.next_game();
+        assert!(exploiting.history.is_empty() && exploiting.opponent_model == seen);
+        send(&mut exploiting, r#"{"command":"start"}"#);
+        send(&mut exploiting, r#"{"command":"start","force":true}"#);
+        assert!(exploiting.history.len() == 1 && exploiting.opponent_model == seen);
+        exploiting.reset();
+        assert!(exploiting.opponent_model == OpponentModel::default());
+    }
 }
\ No newline at end of file